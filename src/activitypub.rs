@@ -0,0 +1,390 @@
+/// This module reads instances through ActivityPub, for instances restricting their REST API :
+/// the instance actor is resolved with WebFinger, then its collections are walked, and the
+/// ActivityStreams videos are converted into the model of the REST API.
+use crate::peertube_api::{Account, Category, Channel, Language, Licence, Privacy, State, Video};
+use crate::seed_sources::normalize_host;
+use serde_json::Value;
+use std::future::Future;
+use std::str::FromStr;
+
+/// Media type of the ActivityStreams documents
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+
+/// Media type of the WebFinger responses
+pub const JRD_JSON: &str = "application/jrd+json";
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/** Name of the actor representing a PeerTube instance */
+const INSTANCE_ACTOR: &str = "peertube";
+
+/// How instances are read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fetcher {
+    /// The REST API of PeerTube
+    Rest,
+    ActivityPub,
+    /// The REST API, falling back to ActivityPub for instances not listing their videos
+    Auto,
+}
+
+impl FromStr for Fetcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rest" => Ok(Fetcher::Rest),
+            "activitypub" => Ok(Fetcher::ActivityPub),
+            "auto" => Ok(Fetcher::Auto),
+            _ => Err(format!(
+                "Unknown fetcher {}, expected rest, activitypub or auto",
+                s
+            )),
+        }
+    }
+}
+
+/// URL resolving the instance actor of a host with WebFinger
+pub fn webfinger_url(host: &str) -> String {
+    format!(
+        "https://{}/.well-known/webfinger?resource=acct:{}@{}",
+        host, INSTANCE_ACTOR, host
+    )
+}
+
+/// URL of the ActivityPub actor in a WebFinger response
+pub fn webfinger_actor(json: &Value) -> Option<String> {
+    json["links"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|link| {
+            link["rel"] == "self"
+                && link["type"]
+                    .as_str()
+                    .is_some_and(|t| t.contains("activity+json"))
+        })
+        .and_then(|link| link["href"].as_str())
+        .map(String::from)
+}
+
+/// Identifier of an object given either inline or as a link
+pub fn object_id(object: &Value) -> Option<&str> {
+    object.as_str().or_else(|| object["id"].as_str())
+}
+
+/// Collects the items of an `OrderedCollection` or a `Collection`, following its pages up to
+/// `max_pages`. The collection and its pages are fetched with `fetch`.
+pub async fn walk_collection<F, Fut, E>(
+    url: &str,
+    max_pages: u64,
+    mut fetch: F,
+) -> Result<Vec<Value>, E>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<Value, E>>,
+{
+    let collection = fetch(url.to_string()).await?;
+    let mut items = page_items(&collection);
+    let mut next = collection
+        .get("first")
+        .and_then(|first| match first {
+            // Some servers inline the first page
+            Value::Object(_) => {
+                items.extend(page_items(first));
+                first["next"].as_str()
+            }
+            _ => first.as_str(),
+        })
+        .map(String::from);
+    let mut pages = 0;
+    while let Some(url) = next {
+        if pages >= max_pages {
+            break;
+        }
+        pages += 1;
+        let page = fetch(url).await?;
+        let page_items = page_items(&page);
+        if page_items.is_empty() {
+            break;
+        }
+        items.extend(page_items);
+        next = page["next"].as_str().map(String::from);
+    }
+    Ok(items)
+}
+
+fn page_items(page: &Value) -> Vec<Value> {
+    page["orderedItems"]
+        .as_array()
+        .or_else(|| page["items"].as_array())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Host of an actor, from its URL
+pub fn actor_host(actor: &Value) -> Option<String> {
+    object_id(actor).and_then(normalize_host)
+}
+
+/// Parses the `PT1H2M3S` durations of ActivityStreams, in seconds
+pub fn parse_duration(duration: &str) -> Option<i64> {
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in duration.strip_prefix("PT")?.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'H' | 'M' | 'S' => {
+                let value: i64 = number.parse().ok()?;
+                number.clear();
+                seconds += value
+                    * match c {
+                        'H' => 3600,
+                        'M' => 60,
+                        _ => 1,
+                    };
+            }
+            _ => return None,
+        }
+    }
+    Some(seconds)
+}
+
+fn path_of(url: &str) -> String {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    match without_scheme.find('/') {
+        Some(index) => without_scheme[index..].to_string(),
+        None => "/".to_string(),
+    }
+}
+
+/// Name of an actor, the last segment of its URL
+fn actor_name(url: &str) -> String {
+    url.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Username and display name of an actor object
+pub fn actor_names(actor: &Value) -> Option<(String, String)> {
+    let username = actor["preferredUsername"].as_str()?;
+    let display_name = actor["name"].as_str().unwrap_or(username);
+    Some((username.to_string(), display_name.to_string()))
+}
+
+/// Whether an object is addressed to the public collection, which unlisted and private videos
+/// are not
+pub fn is_public(object: &Value) -> bool {
+    object["to"]
+        .as_array()
+        .is_some_and(|to| to.iter().any(|actor| actor == PUBLIC))
+}
+
+fn attributed_to(video: &Value, kind: &str) -> Option<String> {
+    video["attributedTo"]
+        .as_array()?
+        .iter()
+        .find(|actor| actor["type"] == kind)
+        .and_then(object_id)
+        .map(String::from)
+}
+
+/// Converts a public ActivityStreams `Video` object, read from `instance`, into a video of the
+/// REST API. Counters only exposed as collections, such as likes, are left at 0, and the display
+/// names of the account and channel are the names from their URLs until set from their actors.
+pub fn video_from_activitypub(object: &Value, instance: &str) -> Option<Video> {
+    if object["type"] != "Video" || !is_public(object) {
+        return None;
+    }
+    let account_url = attributed_to(object, "Person")?;
+    let channel_url = attributed_to(object, "Group")?;
+    let host = normalize_host(&account_url)?;
+    let uuid = object["uuid"].as_str()?.to_string();
+    // The smallest icon is the thumbnail, the largest the preview
+    let mut icons: Vec<&Value> = object["icon"].as_array().into_iter().flatten().collect();
+    icons.sort_by_key(|icon| icon["width"].as_u64().unwrap_or(0));
+    let icon_path = |icon: Option<&&Value>| {
+        icon.and_then(|icon| icon["url"].as_str())
+            .map(path_of)
+            .unwrap_or_default()
+    };
+    let published = object["published"].as_str()?.to_string();
+    Some(Video {
+        id: None,
+        embed_path: format!("/videos/embed/{}", uuid),
+        uuid,
+        created_at: published.clone(),
+        updated_at: object["updated"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| published.clone()),
+        published_at: published,
+        originally_published_at: object["originallyPublishedAt"].as_str().map(String::from),
+        category: Category {
+            id: object["category"]["identifier"]
+                .as_str()
+                .and_then(|id| id.parse().ok()),
+            label: object["category"]["name"]
+                .as_str()
+                .unwrap_or("Unknown")
+                .to_string(),
+        },
+        licence: Licence {
+            id: object["licence"]["identifier"]
+                .as_str()
+                .and_then(|id| id.parse().ok()),
+            label: object["licence"]["name"]
+                .as_str()
+                .unwrap_or("Unknown")
+                .to_string(),
+        },
+        language: Language {
+            id: object["language"]["identifier"].as_str().map(String::from),
+            label: object["language"]["name"]
+                .as_str()
+                .unwrap_or("Unknown")
+                .to_string(),
+        },
+        privacy: Privacy {
+            id: Some(1),
+            label: "Public".to_string(),
+        },
+        description: object["content"].as_str().map(String::from),
+        duration: object["duration"]
+            .as_str()
+            .and_then(parse_duration)
+            .unwrap_or(0),
+        is_local: host == instance,
+        thumbnail_path: icon_path(icons.first()),
+        preview_path: icon_path(icons.last()),
+        views: object["views"].as_i64().unwrap_or(0),
+        likes: 0,
+        dislikes: 0,
+        nsfw: object["sensitive"].as_bool().unwrap_or(false),
+        wait_transcoding: object["waitTranscoding"].as_bool(),
+        state: object["state"].as_i64().map(|id| State {
+            id: Some(id),
+            label: String::new(),
+        }),
+        blacklisted: None,
+        blacklisted_reason: None,
+        account: Account {
+            id: None,
+            name: actor_name(&account_url),
+            display_name: actor_name(&account_url),
+            url: account_url,
+            host: host.clone(),
+            avatar: None,
+        },
+        channel: Channel {
+            id: None,
+            name: actor_name(&channel_url),
+            display_name: actor_name(&channel_url),
+            host: normalize_host(&channel_url).unwrap_or(host),
+            url: channel_url,
+            avatar: None,
+        },
+        name: object["name"].as_str()?.to_string(),
+        tags: object["tag"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|tag| tag["type"] == "Hashtag")
+            .filter_map(|tag| tag["name"].as_str())
+            .map(String::from)
+            .collect(),
+        hidden: false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::activitypub::{
+        actor_names, parse_duration, video_from_activitypub, walk_collection, webfinger_actor,
+    };
+    use futures::executor::block_on;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn activitypub() {
+        assert_eq!(parse_duration("PT567S"), Some(567));
+        assert_eq!(parse_duration("PT1H2M3S"), Some(3723));
+        assert_eq!(parse_duration("567"), None);
+
+        let json = include_str!("../tests/video_activitypub.json");
+        let mut object: Value = serde_json::from_str(json).unwrap();
+        let video = video_from_activitypub(&object, "raptube.antipub.org").unwrap();
+        assert_eq!(video.uuid, "9e672bff-0bc5-4021-8a50-7dce52d0edfa");
+        assert_eq!(video.duration, 567);
+        assert_eq!(video.account.host, "raptube.antipub.org");
+        assert_eq!(video.channel.name, "renaudf_channel");
+        assert_eq!(video.category.id, Some(11));
+        assert_eq!(video.tags, vec!["agro-industrie"]);
+        assert_eq!(
+            video.thumbnail_path,
+            "/static/thumbnails/9e672bff-0bc5-4021-8a50-7dce52d0edfa.jpg"
+        );
+        assert_eq!(
+            video.preview_path,
+            "/static/previews/9e672bff-0bc5-4021-8a50-7dce52d0edfa.jpg"
+        );
+        assert_eq!(video.privacy.label, "Public");
+        assert!(video.is_local);
+        assert!(
+            !video_from_activitypub(&object, "other.example")
+                .unwrap()
+                .is_local
+        );
+
+        object["to"] = json!(["https://raptube.antipub.org/accounts/renaudf/followers"]);
+        assert!(video_from_activitypub(&object, "raptube.antipub.org").is_none());
+
+        let actor = json!({ "preferredUsername": "renaudf_channel", "name": "Renaud F" });
+        assert_eq!(
+            actor_names(&actor),
+            Some(("renaudf_channel".to_string(), "Renaud F".to_string()))
+        );
+    }
+
+    #[test]
+    fn collections() {
+        let webfinger = json!({
+            "links": [{
+                "rel": "self",
+                "type": "application/activity+json",
+                "href": "https://peertube.example/accounts/peertube"
+            }]
+        });
+        assert_eq!(
+            webfinger_actor(&webfinger).unwrap(),
+            "https://peertube.example/accounts/peertube"
+        );
+        assert!(webfinger_actor(&json!({ "links": [] })).is_none());
+
+        let fetch = |url: String| async move {
+            match url.as_str() {
+                "https://peertube.example/outbox" => Ok(json!({
+                    "type": "OrderedCollection",
+                    "first": "https://peertube.example/outbox?page=1"
+                })),
+                "https://peertube.example/outbox?page=1" => Ok(json!({
+                    "orderedItems": [1, 2],
+                    "next": "https://peertube.example/outbox?page=2"
+                })),
+                "https://peertube.example/outbox?page=2" => Ok(json!({ "orderedItems": [3] })),
+                _ => Err(url),
+            }
+        };
+        let items = block_on(walk_collection(
+            "https://peertube.example/outbox",
+            10,
+            fetch,
+        ));
+        assert_eq!(items.unwrap(), vec![json!(1), json!(2), json!(3)]);
+        let items = block_on(walk_collection("https://peertube.example/outbox", 1, fetch));
+        assert_eq!(items.unwrap().len(), 2);
+        assert!(block_on(walk_collection("https://other.example", 10, fetch)).is_err());
+    }
+}
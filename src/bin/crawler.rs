@@ -1,14 +1,16 @@
 #![allow(unused_imports)]
+use async_std::channel::{bounded, Receiver, Sender};
 use async_std::fs::{File, OpenOptions};
 use async_std::io::prelude::*;
 use async_std::io::{BufReader, BufWriter, Write};
 use async_std::sync::{Arc, Mutex};
-use futures::future::{join3, join_all};
+use async_std::task;
+use futures::future::{join, join3, join4, join_all, select};
 use futures::{Future, FutureExt};
+use isahc::http::StatusCode;
 use isahc::prelude::*;
 use log::*;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use serde_json::to_string_pretty;
 use serde_json::{from_reader, to_string};
 use std::collections::vec_deque::VecDeque;
@@ -16,38 +18,117 @@ use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+use chrono::{DateTime, Local, Utc};
 use futures::executor::block_on;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use peertube_lib::elastic::create_mappings;
+use peertube_lib::activitypub::{
+    actor_host, actor_names, is_public, object_id, video_from_activitypub, walk_collection,
+    webfinger_actor, webfinger_url, Fetcher, ACTIVITY_JSON, JRD_JSON,
+};
+use peertube_lib::crawl_limits::{parse_sample_rate, CrawlLimits, Sampler};
+use peertube_lib::crawl_output::{
+    create_snapshot, latest_snapshot, link_crawl_files, resume_crawl_file, rotate_snapshots,
+    Compression, ResumePoint, VideoWriter,
+};
+use peertube_lib::crawl_report::{http_error_kind, CrawlReport};
+use peertube_lib::elastic::EsConfig;
+use peertube_lib::host_pace::{escalate, HostPace, Timeouts, MAX_PAGE_SIZE};
+use peertube_lib::instance_filter::{InstanceFilter, Verdict};
 use peertube_lib::instance_storage::InstanceDb;
-use peertube_lib::peertube_api::fetch_instance_list_from_joinpeertube;
+use peertube_lib::json_log::JsonLogger;
+use peertube_lib::metrics::{serve_metrics, CrawlMetrics};
 use peertube_lib::peertube_api::Video;
+use peertube_lib::peertube_api::{parse_blocklist_page, parse_remote_hosts, parse_server_version};
+use peertube_lib::progress::{Counter, Progress, ProgressMode};
+use peertube_lib::retry::{
+    classify_error, classify_status, parse_retry_after, ErrorClass, RetryPolicy,
+};
+use peertube_lib::schedule::{RESEED_INTERVAL_HOURS, RESEED_RETRY_HOURS};
+use peertube_lib::seed_sources::{
+    collect_seeds, FediverseObserver, HostFile, HostList, JoinPeertube, SeedSource, SeedSpec,
+    KNOWN_SOURCE,
+};
+use peertube_lib::video_policy::{NsfwPolicy, PolicyBucket, PolicyStats, VideoPolicy};
+use peertube_lib::video_storage::{open_storage, StorageKind, VideoStorage};
+use peertube_lib::{endpoint_log, host_log};
+use signal_hook::consts::{SIGINT, SIGTERM};
 use std::cmp::min;
 use std::convert::TryInto;
-use std::f32::MAX;
+use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::io::Stdout;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use stderrlog;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use stderrlog::ColorChoice;
 
 const OUTPUT_DIR: &str = "crawled/";
 
-/** Maximum number of videos to fetch from an instance */
-const MAX_VIDEOS: u64 = 100;
+/** Media type accepted from the REST API */
+const JSON: &str = "application/json";
 
-const LIMIT: u64 = 0;
+/** Delay between two checks for due instances in daemon mode, in seconds */
+const DAEMON_POLL_SECS: u64 = 60;
+
+/** Key of the date of the last fetch of the seed sources in the crawler state */
+const LAST_SEED_KEY: &str = "last_seed";
+
+/** Key of the date of the last fetch of the seed sources, successful or not */
+const LAST_SEED_ATTEMPT_KEY: &str = "last_seed_attempt";
+
+/** Pages read from each ActivityPub collection, unless limited by --max-pages */
+const ACTIVITYPUB_MAX_PAGES: u64 = 100;
+
+/** Most videos sent to the video storage at once */
+const STORE_BATCH_SIZE: usize = 1000;
+
+/** Pages of videos waiting for the video storage before the crawl waits for it */
+const STORE_QUEUE_SIZE: usize = 64;
+
+/** Requests made for a page of videos, timeouts with smaller pages included, as a multiple of
+ * the attempts allowed by the retry policy */
+const PAGE_ATTEMPTS_FACTOR: u32 = 2;
 
 #[derive(Clone)]
 struct CrawlCtx {
     pub nodes: Arc<Mutex<HashSet<String>>>,
-    pub result: Arc<Mutex<HashSet<APIInstance>>>,
     pub count: Arc<Mutex<u64>>,
     pub db: Arc<Mutex<InstanceDb>>,
+    pub filter: Arc<Mutex<InstanceFilter>>,
+    pub import_blocklists: bool,
+    pub fetcher: Fetcher,
+    /// Whether the instances found in follower lists are crawled too, or only recorded
+    pub recursive: bool,
+    pub limits: CrawlLimits,
+    /// Whether federated videos are paged to discover the instances they come from
+    pub discover_from_videos: bool,
+    /// Maximum number of pages of federated videos fetched from each instance
+    pub discovery_pages: u64,
+    /// Picks the instances to crawl in sampling mode
+    pub sampler: Option<Arc<Mutex<Sampler>>>,
+    pub policy: VideoPolicy,
+    pub policy_stats: Arc<Mutex<PolicyStats>>,
     pub http_client: Arc<HttpClient>,
-    pub instance_bar: ProgressBar,
-    pub video_bar: ProgressBar,
+    pub retry_policy: RetryPolicy,
+    pub timeouts: Timeouts,
+    /// Latency and page size of the instances being crawled
+    pub pace: Arc<Mutex<HashMap<String, HostPace>>>,
+    pub output_dir: PathBuf,
+    pub compression: Compression,
+    /// Pages of videos to store, written in batches by the storage thread
+    pub storage: Sender<Vec<Video>>,
+    pub report: Arc<Mutex<CrawlReport>>,
+    pub metrics: Arc<CrawlMetrics>,
+    pub instance_bar: Counter,
+    pub video_bar: Counter,
+    /// Set by SIGTERM and SIGINT : the fetches stop between two pages and write what they got
+    pub shutdown: Arc<AtomicBool>,
+}
+
+impl CrawlCtx {
+    fn stopping(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -80,150 +161,639 @@ impl Hash for APIInstance {
 }
 impl Eq for APIInstance {}
 
-async fn queue_for_crawling(item: String, ctx: CrawlCtx) -> Pin<Box<dyn Future<Output = ()>>> {
+/// Checks an instance against the allow and deny lists, recording it in the database with the
+/// reason of the block. Exclusions are lifted by `reset_blocked_instances` on the next round.
+async fn is_crawlable(item: &str, ctx: &CrawlCtx) -> bool {
+    let check = ctx.filter.lock().await.check(item);
+    match check {
+        Verdict::Allowed => true,
+        Verdict::Denied(reason) | Verdict::Excluded(reason) => {
+            host_log!(Level::Trace, item, "Skipped : {}", reason);
+            ctx.db
+                .lock()
+                .await
+                .insert_blocked_instance(item.to_string(), reason);
+            false
+        }
+    }
+}
+
+/// Counts an instance against the sampling rate and the instance limit, returning whether it
+/// should be crawled
+async fn admit(item: &str, ctx: &CrawlCtx) -> bool {
+    if let Some(sampler) = &ctx.sampler {
+        if !sampler.lock().await.keep() {
+            host_log!(Level::Trace, item, "Skipped : not sampled");
+            return false;
+        }
+    }
+    let mut count = ctx.count.lock().await;
+    if !ctx.limits.allows_instance(*count) {
+        host_log!(
+            Level::Trace,
+            item,
+            "Skipped : maximum number of instances reached"
+        );
+        return false;
+    }
+    *count += 1;
+    ctx.instance_bar.inc_length(1);
+    ctx.metrics.instances_queued.inc();
+    true
+}
+
+/// Schedules the crawl of an instance found `depth` hops away from the seeds, unless it is already
+/// queued or out of the crawl limits
+async fn queue_for_crawling(
+    item: String,
+    depth: u32,
+    ctx: CrawlCtx,
+) -> Pin<Box<dyn Future<Output = ()>>> {
     let mut res: Pin<Box<dyn Future<Output = ()>>> = Box::pin(async {});
+    if !is_crawlable(&item, &ctx).await {
+        return res;
+    }
     ctx.db.lock().await.insert_instance(item.clone());
-    if !ctx.nodes.lock().await.contains(&item) {
-        ctx.nodes.lock().await.insert(item.clone());
-        let mut count_val = ctx.count.lock().await;
-        if *count_val < LIMIT || LIMIT == 0 {
-            *count_val += 1;
-            ctx.instance_bar.inc_length(1);
-            trace!("[{}] Scheduled", item);
-            res = Box::pin(fetch(item, ctx.clone()));
-        } else {
-            //trace!("[{}] Skipped : reached maximum depth", item);
-        }
-    } else {
-        //trace!("[{}] Skipped: already in queue", item);
+    if !ctx.recursive || !ctx.limits.allows_depth(depth) {
+        return res;
+    }
+    if ctx.nodes.lock().await.insert(item.clone()) && admit(&item, &ctx).await {
+        host_log!(Level::Trace, item, "Scheduled");
+        res = Box::pin(fetch(item, depth, ctx.clone()));
     }
     res
 }
 
-async fn write_to_file(filename: String, data: Vec<Video>) {
-    match File::create(filename.clone()).await {
-        Ok(file) => {
-            let lines: String = data.into_iter().fold("".to_string(), |mut acc, o| {
-                acc += &to_string(&o).unwrap();
-                acc += "\n";
-                acc
-            });
-            let mut writer = BufWriter::new(file);
-            if let Err(e) = writer.write(&(lines.as_bytes())).await {
-                error!("Error while writing videos to {} : {}", filename, e);
+/// Appends a page of videos to the crawl file of an instance, creating the file on the first page,
+/// and stores them in the video storage. The file is written on the blocking thread pool.
+async fn write_videos(
+    writer: &mut Option<VideoWriter>,
+    name: &str,
+    ctx: &CrawlCtx,
+    videos: &[Video],
+) {
+    if videos.is_empty() {
+        return;
+    }
+    if ctx.storage.send(videos.to_vec()).await.is_err() {
+        host_log!(
+            Level::Error,
+            name,
+            "Failed to store videos : the storage thread stopped"
+        );
+    }
+    let mut current = writer.take();
+    let (name, dir, compression) = (name.to_string(), ctx.output_dir.clone(), ctx.compression);
+    let videos = videos.to_vec();
+    *writer = task::spawn_blocking(move || {
+        if current.is_none() {
+            match VideoWriter::create(&dir, &name, compression) {
+                Ok(w) => current = Some(w),
+                Err(e) => host_log!(Level::Error, name, "Failed to create crawl file : {}", e),
+            }
+        }
+        if let Some(w) = &mut current {
+            for video in &videos {
+                if let Err(e) = w.write_video(video) {
+                    host_log!(
+                        Level::Error,
+                        name,
+                        "Error while writing videos to {:?} : {}",
+                        w.path(),
+                        e
+                    );
+                    break;
+                }
             }
         }
-        Err(e) => error!("{}", e),
+        current
+    })
+    .await;
+}
+
+/// Writes the end of the crawl file of an instance, on the blocking thread pool
+async fn finish_videos(writer: Option<VideoWriter>, name: &str) {
+    if let Some(w) = writer {
+        if let Err(e) = task::spawn_blocking(move || w.finish()).await {
+            host_log!(Level::Error, name, "Failed to write crawl file : {}", e);
+        }
     }
 }
 
-async fn crawl_from_instances(
-    instances: Vec<String>,
-    nodes: Arc<Mutex<HashSet<String>>>,
-    result: Arc<Mutex<HashSet<APIInstance>>>,
-    count: Arc<Mutex<u64>>,
-    db: Arc<Mutex<InstanceDb>>,
-    http_client: Arc<HttpClient>,
-    instance_bar: ProgressBar,
-    video_bar: ProgressBar,
-) {
+async fn crawl_from_instances(instances: Vec<String>, ctx: CrawlCtx) {
     let mut futures = vec![];
-    let ctx = CrawlCtx {
-        nodes,
-        result,
-        count,
-        db,
-        http_client,
-        instance_bar,
-        video_bar,
-    };
     for instance in instances {
-        let f = fetch(instance, ctx.clone());
-        futures.push(f);
+        if is_crawlable(&instance, &ctx).await
+            && ctx.nodes.lock().await.insert(instance.clone())
+            && admit(&instance, &ctx).await
+        {
+            let f = fetch(instance, 0, ctx.clone());
+            futures.push(f);
+        }
     }
     join_all(futures).await;
 }
 
-async fn fetch_video(name: String, http_client: Arc<HttpClient>, video_bar: ProgressBar) {
-    let mut videos_to_fetch: u64 = 1;
+enum FetchError {
+    Http(isahc::Error),
+    /// The server answered with an error status
+    Status(StatusCode),
+    /// The body of the response could not be read to its end
+    Read(isahc::Error),
+    /// The response is not valid JSON, the body is kept for debugging
+    InvalidJson(serde_json::Error, String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Http(e) => write!(f, "{}", e),
+            FetchError::Status(status) => write!(f, "{}", status),
+            FetchError::Read(e) => write!(f, "failed to read the response : {}", e),
+            FetchError::InvalidJson(e, _) => write!(f, "invalid json : {}", e),
+        }
+    }
+}
+
+/// Fetches a JSON document from an instance, retrying transient failures according to the retry
+/// policy, and recording the requests in the metrics. Failures are counted as errors of the
+/// instance in the crawl report when `record_errors` is set, which auxiliary requests leave out.
+async fn get_json(
+    name: &str,
+    endpoint: &str,
+    url: &str,
+    base_timeout: Duration,
+    record_errors: bool,
+    ctx: &CrawlCtx,
+) -> Result<serde_json::Value, FetchError> {
+    let mut attempts = ctx.retry_policy.max_retries + 1;
+    request_json(
+        name,
+        endpoint,
+        url,
+        JSON,
+        base_timeout,
+        record_errors,
+        &mut attempts,
+        ctx,
+    )
+    .await
+    .map(|(json, _)| json)
+}
+
+/// Same as `get_json` for ActivityPub and WebFinger documents, which are only served to requests
+/// accepting their media type
+async fn get_activity(
+    name: &str,
+    endpoint: &str,
+    url: &str,
+    accept: &str,
+    base_timeout: Duration,
+    record_errors: bool,
+    ctx: &CrawlCtx,
+) -> Result<serde_json::Value, FetchError> {
+    let mut attempts = ctx.retry_policy.max_retries + 1;
+    request_json(
+        name,
+        endpoint,
+        url,
+        accept,
+        base_timeout,
+        record_errors,
+        &mut attempts,
+        ctx,
+    )
+    .await
+    .map(|(json, _)| json)
+}
+
+/// Same as `get_json`, asking for the `accept` media type and making at most `attempts`
+/// requests, which is decremented for each one, and also returning the duration of the
+/// successful request
+#[allow(clippy::too_many_arguments)]
+async fn request_json(
+    name: &str,
+    endpoint: &str,
+    url: &str,
+    accept: &str,
+    base_timeout: Duration,
+    record_errors: bool,
+    attempts: &mut u32,
+    ctx: &CrawlCtx,
+) -> Result<(serde_json::Value, Duration), FetchError> {
+    let mut retry = 0;
+    let mut timeout = match ctx.pace.lock().await.get(name) {
+        Some(pace) => pace.timeout(base_timeout, ctx.timeouts.max),
+        None => base_timeout,
+    };
+    loop {
+        *attempts = attempts.saturating_sub(1);
+        let start = Instant::now();
+        let request = Request::get(url)
+            .header("Accept", accept)
+            .timeout(timeout)
+            .body(())
+            .unwrap();
+        let (error, class, retry_after) = match ctx.http_client.send_async(request).await {
+            Ok(mut resp) => {
+                let status = resp.status();
+                let body = resp.text();
+                if let Some(pace) = ctx.pace.lock().await.get_mut(name) {
+                    pace.observe(start.elapsed());
+                }
+                let label = match &body {
+                    Ok(body) => {
+                        ctx.metrics.bytes_fetched.inc_by(body.len() as u64);
+                        status.as_str()
+                    }
+                    Err(_) => "body_read",
+                };
+                ctx.metrics
+                    .observe_request(endpoint, label, start.elapsed().as_secs_f64());
+                let mut report = ctx.report.lock().await;
+                report.instance(name).reachable = true;
+                match (body, classify_status(status)) {
+                    (Err(e), _) => {
+                        if record_errors {
+                            report.add_error(name, "body_read");
+                        }
+                        (FetchError::Read(e), ErrorClass::Transient, None)
+                    }
+                    (Ok(body), None) => {
+                        let elapsed = start.elapsed();
+                        return serde_json::from_str(&body)
+                            .map(|json| (json, elapsed))
+                            .map_err(|e| {
+                                if record_errors {
+                                    report.add_error(name, "invalid_json");
+                                }
+                                FetchError::InvalidJson(e, body)
+                            });
+                    }
+                    (Ok(_), Some(class)) => {
+                        if record_errors {
+                            report.add_error(name, &format!("http_{}", status.as_u16()));
+                        }
+                        let retry_after = resp
+                            .headers()
+                            .get("Retry-After")
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| parse_retry_after(value, Utc::now()));
+                        (FetchError::Status(status), class, retry_after)
+                    }
+                }
+            }
+            Err(e) => {
+                let kind = http_error_kind(&e);
+                ctx.metrics
+                    .observe_request(endpoint, kind, start.elapsed().as_secs_f64());
+                if record_errors {
+                    ctx.report.lock().await.add_error(name, kind);
+                }
+                if let isahc::Error::Timeout = e {
+                    timeout = escalate(timeout, ctx.timeouts.max);
+                }
+                let class = classify_error(&e);
+                (FetchError::Http(e), class, None)
+            }
+        };
+        if class == ErrorClass::Permanent
+            || retry >= ctx.retry_policy.max_retries
+            || *attempts == 0
+            || ctx.stopping()
+        {
+            return Err(error);
+        }
+        let delay = ctx.retry_policy.delay(retry, retry_after);
+        endpoint_log!(Level::Trace, name, endpoint, "Retrying in {:?}", delay);
+        task::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+/// Keeps the videos from allowed hosts and applies the video policy, returning the videos to store
+async fn select_videos(videos: Vec<Video>, ctx: &CrawlCtx) -> Vec<Video> {
+    let filter = ctx.filter.lock().await;
+    let mut policy_stats = ctx.policy_stats.lock().await;
+    let mut selected = vec![];
+    for mut video in videos {
+        if filter.is_allowed(&video.account.host) && filter.is_allowed(&video.channel.host) {
+            let bucket = ctx.policy.apply(&mut video);
+            policy_stats.add(bucket);
+            match bucket {
+                PolicyBucket::Indexed | PolicyBucket::Hidden => selected.push(video),
+                _ => (),
+            }
+        }
+    }
+    selected
+}
+
+/// Fetches the videos of an instance, starting where the previous crawl stopped if it failed
+/// midway. Pages get smaller when the instance is slow. Returns false when the instance does not
+/// list its videos at all.
+async fn fetch_video(name: String, ctx: CrawlCtx) -> bool {
+    let video_bar = ctx.video_bar.clone();
+    let point = ctx.db.lock().await.get_resume_point(&name);
+    // Start of the next page, and number of videos written to the crawl file
+    let (mut index, mut written) = (0, 0);
+    let mut writer: Option<VideoWriter> = None;
+    if let Some(point) = point {
+        // The crawl file starts with the videos fetched before the interruption
+        let (host, dir, compression) = (name.clone(), ctx.output_dir.clone(), ctx.compression);
+        let resumed = task::spawn_blocking(move || {
+            resume_crawl_file(Path::new(OUTPUT_DIR), &dir, &host, compression, point)
+        })
+        .await;
+        match resumed {
+            Ok(Some(w)) => {
+                endpoint_log!(
+                    Level::Info,
+                    name,
+                    "/videos/",
+                    "Resuming at video {}",
+                    point.offset
+                );
+                index = point.offset;
+                written = point.lines;
+                writer = Some(w);
+            }
+            Ok(None) => {
+                endpoint_log!(
+                    Level::Info,
+                    name,
+                    "/videos/",
+                    "No crawl file holds the first {} videos, restarting",
+                    point.lines
+                );
+            }
+            Err(e) => {
+                host_log!(Level::Warn, name, "Failed to resume the crawl file : {}", e);
+            }
+        }
+    }
+    let mut videos_to_fetch: u64 = ctx.limits.videos_to_fetch(index + 1);
     let mut fetched_total: bool = false;
-    let mut index: u64 = 0;
+    let mut failed = false;
+    // Whether the crawl stops before the last video, because of --max-videos or --max-pages
+    let mut limited = false;
+    let mut interrupted = false;
+    let mut pages: u64 = 0;
     let instance_url = "https://".to_owned() + name.clone().as_str();
-    let filename = OUTPUT_DIR.to_owned() + &name + ".json";
-    let mut videos: Vec<Video> = vec![];
+    // Requests left for the current page, shared by the retries that follow a smaller page size
+    let mut attempts = PAGE_ATTEMPTS_FACTOR * (ctx.retry_policy.max_retries + 1);
     while index < videos_to_fetch {
+        if ctx.stopping() {
+            interrupted = true;
+            break;
+        }
+        if !ctx.limits.allows_page(pages) {
+            limited = true;
+            endpoint_log!(
+                Level::Trace,
+                name,
+                "/videos/",
+                "Reached the maximum number of pages"
+            );
+            break;
+        }
+        pages += 1;
+        let mut page_size = match ctx.pace.lock().await.get(&name) {
+            Some(pace) => pace.page_size,
+            None => MAX_PAGE_SIZE,
+        };
+        if let Some(max) = ctx.limits.max_videos {
+            page_size = page_size.min(max - index);
+        }
         let query_videos = instance_url.clone()
             + "/api/v1/videos?count="
-            + &MAX_VIDEOS.to_string()
+            + &page_size.to_string()
             + "&filter=local"
             + "&start="
             + &index.to_string();
         video_bar.tick();
-        let request = Request::get(&query_videos).body(()).unwrap();
-        match http_client.send_async(request).await {
-            Ok(mut resp) => match resp.json::<serde_json::Value>() {
-                Ok(json) => {
-                    if let Some(data) = json["data"].as_array() {
-                        index += data.len() as u64;
-                        if let Some(total) = json["total"].as_u64() {
-                            if !fetched_total {
-                                videos_to_fetch = total;
-                                fetched_total = true;
-                                video_bar.inc_length(videos_to_fetch);
-                            }
+        match request_json(
+            &name,
+            "/videos",
+            &query_videos,
+            JSON,
+            ctx.timeouts.videos,
+            true,
+            &mut attempts,
+            &ctx,
+        )
+        .await
+        {
+            Ok((json, elapsed)) => {
+                attempts = PAGE_ATTEMPTS_FACTOR * (ctx.retry_policy.max_retries + 1);
+                if let Some(pace) = ctx.pace.lock().await.get_mut(&name) {
+                    let timeout = pace.timeout(ctx.timeouts.videos, ctx.timeouts.max);
+                    pace.adapt_page_size(elapsed, timeout);
+                }
+                if let Some(data) = json["data"].as_array() {
+                    if data.is_empty() {
+                        break;
+                    }
+                    index += data.len() as u64;
+                    if let Some(total) = json["total"].as_u64() {
+                        if !fetched_total {
+                            videos_to_fetch = ctx.limits.videos_to_fetch(total);
+                            limited |= videos_to_fetch < total;
+                            fetched_total = true;
+                            video_bar.inc_length(videos_to_fetch);
                         }
-                        video_bar.inc(data.len() as u64);
-                        for value in data.iter() {
-                            match serde_json::from_value(value.clone()) {
-                                Ok(video) => videos.push(video),
-                                Err(e) => {
-                                    trace!(
-                                        "Failed to parse peertube response from {}: {}",
-                                        name,
-                                        e
-                                    );
-                                }
+                    }
+                    video_bar.inc(data.len() as u64);
+                    let mut videos: Vec<Video> = vec![];
+                    for value in data.iter() {
+                        match serde_json::from_value::<Video>(value.clone()) {
+                            Ok(video) => {
+                                ctx.metrics.videos_parsed.inc();
+                                videos.push(video);
+                            }
+                            Err(e) => {
+                                ctx.metrics.videos_failed.inc();
+                                endpoint_log!(
+                                    Level::Trace,
+                                    name,
+                                    "/videos/",
+                                    "Failed to parse peertube response : {}",
+                                    e
+                                );
                             }
                         }
-                    } else {
-                        error!(
-                            "{}",
-                            format!("[{}][{}] - JSON : {:?}", name, "/videos/", json).as_str()
-                        );
-                        break;
                     }
-                    /*let database = Database::default();
-                    process_videos(database, result);*/
-                }
-                Err(e) => {
-                    trace!(
-                        "Invalid json from {} : {} \nJson : \n{}\n----\n",
-                        name,
-                        e,
-                        resp.text().unwrap_or("Invalid body".to_string())
-                    );
+                    let videos = select_videos(videos, &ctx).await;
+                    ctx.report.lock().await.add_videos(&name, &videos);
+                    write_videos(&mut writer, &name, &ctx, &videos).await;
+                    written += videos.len() as u64;
+                } else {
+                    ctx.report.lock().await.add_error(&name, "invalid_response");
+                    endpoint_log!(Level::Error, name, "/videos/", "JSON : {:?}", json);
+                    failed = true;
                     break;
                 }
-            },
-            Err(e) => {
-                trace!("Failed to fetch videos from {} : {}", query_videos, e);
+                /*let database = Database::default();
+                process_videos(database, result);*/
+            }
+            Err(FetchError::InvalidJson(e, body)) => {
+                endpoint_log!(
+                    Level::Trace,
+                    name,
+                    "/videos/",
+                    "Invalid json : {} \nJson : \n{}\n----\n",
+                    e,
+                    body
+                );
+                failed = true;
+                break;
+            }
+            Err(FetchError::Status(status)) => {
+                endpoint_log!(
+                    Level::Trace,
+                    name,
+                    "/videos/",
+                    "Failed to fetch videos from {} : {}",
+                    query_videos,
+                    status
+                );
+                failed = true;
+                break;
+            }
+            Err(FetchError::Read(e)) => {
+                endpoint_log!(
+                    Level::Trace,
+                    name,
+                    "/videos/",
+                    "Failed to read videos from {} : {}",
+                    query_videos,
+                    e
+                );
+                failed = true;
+                break;
+            }
+            Err(FetchError::Http(e)) => {
+                if matches!(e, isahc::Error::Timeout) && attempts > 0 {
+                    if let Some(pace) = ctx.pace.lock().await.get_mut(&name) {
+                        if pace.shrink_page_size() {
+                            endpoint_log!(
+                                Level::Trace,
+                                name,
+                                "/videos/",
+                                "Timed out, fetching pages of {} videos",
+                                pace.page_size
+                            );
+                            continue;
+                        }
+                    }
+                }
+                endpoint_log!(
+                    Level::Trace,
+                    name,
+                    "/videos/",
+                    "Failed to fetch videos from {} : {}",
+                    query_videos,
+                    e
+                );
+                failed = true;
                 break;
             }
         }
     }
-    info!(
-        "[{}][{}] Fetch complete ({} videos)",
-        name, "/videos/", index
+    // Instances failing from the first page are unreachable rather than partially crawled
+    let resume_point = if (failed || interrupted) && index > 0 {
+        Some(ResumePoint {
+            offset: index,
+            lines: written,
+        })
+    } else {
+        None
+    };
+    if resume_point.is_some() {
+        endpoint_log!(
+            Level::Warn,
+            name,
+            "/videos/",
+            "Partial crawl, stopped at video {}",
+            index
+        );
+    }
+    ctx.db.lock().await.set_resume_point(&name, resume_point);
+    ctx.report.lock().await.instance(&name).complete = !failed && !limited && !interrupted;
+    endpoint_log!(
+        Level::Info,
+        name,
+        "/videos/",
+        "Fetch complete ({} videos)",
+        index
     );
-    if !videos.is_empty() {
-        write_to_file(filename, videos).await;
+    finish_videos(writer, &name).await;
+    !(failed && index == 0)
+}
+
+/// Pages the federated videos known by an instance, and queues the instances they come from.
+/// The videos themselves are not indexed, they are fetched from their origin instance.
+async fn fetch_remote_hosts(name: String, depth: u32, ctx: CrawlCtx) {
+    if !ctx.discover_from_videos {
+        return;
+    }
+    let mut tasks = Vec::new();
+    let mut hosts = HashSet::new();
+    let mut index: u64 = 0;
+    let mut videos_to_fetch: u64 = 1;
+    let mut pages: u64 = 0;
+    while index < videos_to_fetch && pages < ctx.discovery_pages && !ctx.stopping() {
+        pages += 1;
+        let query = "https://".to_owned()
+            + name.as_str()
+            + "/api/v1/videos?count="
+            + &MAX_PAGE_SIZE.to_string()
+            + "&isLocal=false"
+            + "&start="
+            + &index.to_string();
+        ctx.instance_bar.tick();
+        // Failures are left to the metrics : discovery is not part of the crawl of the instance
+        let json = match get_json(
+            &name,
+            "/videos/federated",
+            &query,
+            ctx.timeouts.videos,
+            false,
+            &ctx,
+        )
+        .await
+        {
+            Ok(json) => json,
+            Err(_) => break,
+        };
+        let (page_hosts, count, total) = match parse_remote_hosts(&json, &name) {
+            Some(page) => page,
+            None => break,
+        };
+        index += count;
+        videos_to_fetch = total;
+        for host in page_hosts {
+            if hosts.insert(host.clone()) {
+                tasks.push(queue_for_crawling(host, depth + 1, ctx.clone()).await);
+            }
+        }
     }
+    endpoint_log!(
+        Level::Info,
+        name,
+        "/videos/federated",
+        "Discovery complete ({} hosts in {} videos)",
+        hosts.len(),
+        index
+    );
+    join_all(tasks).await;
 }
 
 async fn fetch_follow(
     api_endpoint: &'static str,
     entry_name: &'static str,
     name: String,
+    depth: u32,
     ctx: CrawlCtx,
     instance: Arc<Mutex<APIInstance>>,
 ) {
@@ -231,7 +801,7 @@ async fn fetch_follow(
     let mut followers_to_fetch: u64 = 1;
     let mut index: u64 = 0;
     let mut fetched_total: bool = false;
-    while index < followers_to_fetch {
+    while index < followers_to_fetch && !ctx.stopping() {
         let query = "https://".to_owned()
             + name.clone().as_str()
             + "/api/v1"
@@ -240,70 +810,86 @@ async fn fetch_follow(
             + "&start="
             + &index.to_string();
         ctx.instance_bar.tick();
-        let request = Request::get(&query).body(()).unwrap();
-        match ctx.http_client.send_async(request).await {
-            Ok(mut req) => match req.json::<serde_json::Value>() {
-                Ok(json) => {
-                    if let Some(total) = json["total"].as_u64() {
-                        if !fetched_total {
-                            followers_to_fetch = total;
-                            fetched_total = true;
-                            ctx.instance_bar.inc_length(total);
-                        }
-                    } else if !fetched_total {
-                        followers_to_fetch = 0;
+        match get_json(&name, api_endpoint, &query, ctx.timeouts.follow, true, &ctx).await {
+            Ok(json) => {
+                if let Some(total) = json["total"].as_u64() {
+                    if !fetched_total {
+                        followers_to_fetch = total;
                         fetched_total = true;
+                        ctx.instance_bar.inc_length(total);
                     }
-                    match json["data"].as_array() {
-                        Some(data) => {
-                            index += data.len() as u64;
-                            ctx.instance_bar.inc(data.len() as u64);
-                            for entry in data {
-                                if let Some(hostname) = entry[entry_name]["host"].as_str() {
-                                    if hostname != name {
-                                        tasks.push(
-                                            queue_for_crawling(hostname.to_string(), ctx.clone())
-                                                .await,
-                                        );
-                                        instance.lock().await.followers.push(hostname.to_owned());
-                                    }
+                } else if !fetched_total {
+                    followers_to_fetch = 0;
+                    fetched_total = true;
+                }
+                match json["data"].as_array() {
+                    Some(data) => {
+                        index += data.len() as u64;
+                        ctx.instance_bar.inc(data.len() as u64);
+                        for entry in data {
+                            if let Some(hostname) = entry[entry_name]["host"].as_str() {
+                                if hostname != name {
+                                    tasks.push(
+                                        queue_for_crawling(
+                                            hostname.to_string(),
+                                            depth + 1,
+                                            ctx.clone(),
+                                        )
+                                        .await,
+                                    );
+                                    instance.lock().await.followers.push(hostname.to_owned());
                                 }
                             }
                         }
-                        None => {
-                            error!(
-                                "{}",
-                                format!(
-                                    "[{}][{}] - Non spec compliant json : {:?}",
-                                    name, api_endpoint, json
-                                )
-                                .as_str()
-                            );
-                            break;
-                        }
+                    }
+                    None => {
+                        ctx.report.lock().await.add_error(&name, "invalid_response");
+                        endpoint_log!(
+                            Level::Error,
+                            name,
+                            api_endpoint,
+                            "Non spec compliant json : {:?}",
+                            json
+                        );
+                        break;
                     }
                 }
-                Err(e) => {
-                    trace!("[{}][{}] Failed to parse json : {} ", name, api_endpoint, e);
-                    break;
-                }
-            },
-            Err(e) => {
+            }
+            Err(FetchError::InvalidJson(e, _)) => {
+                endpoint_log!(
+                    Level::Trace,
+                    name,
+                    api_endpoint,
+                    "Failed to parse json : {} ",
+                    e
+                );
+                break;
+            }
+            Err(FetchError::Status(status)) => {
+                endpoint_log!(Level::Trace, name, api_endpoint, "Failed : {}", status);
+                break;
+            }
+            Err(FetchError::Read(e)) => {
+                endpoint_log!(Level::Trace, name, api_endpoint, "Failed to read : {}", e);
+                break;
+            }
+            Err(FetchError::Http(e)) => {
                 match e {
                     isahc::Error::ConnectFailed
                     | isahc::Error::BadServerCertificate(_)
                     | isahc::Error::SSLConnectFailed(_)
                     | isahc::Error::CouldntResolveHost => {}
-                    _ => trace!("[{}][{}] Failed : {}", name, api_endpoint, e),
+                    _ => endpoint_log!(Level::Trace, name, api_endpoint, "Failed : {}", e),
                 };
                 break;
             }
         }
     }
-    info!(
-        "[{}][{}] Fetch complete ({}/{})",
+    endpoint_log!(
+        Level::Info,
         name,
         api_endpoint,
+        "Fetch complete ({}/{})",
         index,
         if fetched_total {
             followers_to_fetch.to_string()
@@ -314,137 +900,768 @@ async fn fetch_follow(
     join_all(tasks).await;
 }
 
-async fn fetch(name: String, ctx: CrawlCtx) {
-    let instance = Arc::new(Mutex::new(APIInstance::new(name.clone())));
+/// Fetches an ActivityStreams object linked from an instance, such as a video or an actor
+async fn fetch_object(
+    name: &str,
+    url: &str,
+    ctx: &CrawlCtx,
+) -> Result<serde_json::Value, FetchError> {
+    get_activity(
+        name,
+        "object",
+        url,
+        ACTIVITY_JSON,
+        ctx.timeouts.other,
+        false,
+        ctx,
+    )
+    .await
+}
+
+/// Resolves the instance actor of an instance with WebFinger, and fetches it
+async fn resolve_instance_actor(name: &str, ctx: &CrawlCtx) -> Result<serde_json::Value, String> {
+    let url = webfinger_url(name);
+    let json = get_activity(
+        name,
+        "webfinger",
+        &url,
+        JRD_JSON,
+        ctx.timeouts.other,
+        true,
+        ctx,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let actor = webfinger_actor(&json).ok_or("no ActivityPub actor")?;
+    get_activity(
+        name,
+        "actor",
+        &actor,
+        ACTIVITY_JSON,
+        ctx.timeouts.other,
+        true,
+        ctx,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Queues the hosts of the actors of a `followers` or `following` collection of the instance actor
+async fn fetch_activitypub_follow(
+    collection: &str,
+    label: &str,
+    name: &str,
+    depth: u32,
+    ctx: &CrawlCtx,
+) {
+    let mut tasks = Vec::new();
+    let mut hosts = HashSet::new();
+    let max_pages = ctx.limits.max_pages.unwrap_or(ACTIVITYPUB_MAX_PAGES);
+    let fetch = |url: String| async move {
+        get_activity(
+            name,
+            label,
+            &url,
+            ACTIVITY_JSON,
+            ctx.timeouts.follow,
+            false,
+            ctx,
+        )
+        .await
+    };
+    match walk_collection(collection, max_pages, fetch).await {
+        Ok(actors) => {
+            ctx.instance_bar.inc_length(actors.len() as u64);
+            ctx.instance_bar.inc(actors.len() as u64);
+            for host in actors.iter().filter_map(actor_host) {
+                if host != name && hosts.insert(host.clone()) {
+                    tasks.push(queue_for_crawling(host, depth + 1, ctx.clone()).await);
+                }
+            }
+            endpoint_log!(
+                Level::Info,
+                name,
+                label,
+                "Fetch complete ({} actors, {} hosts)",
+                actors.len(),
+                hosts.len()
+            );
+        }
+        Err(e) => endpoint_log!(Level::Trace, name, label, "Failed : {}", e),
+    }
+    join_all(tasks).await;
+}
+
+/// Dereferences the videos of the activities of an outbox, returning the local videos not seen yet
+async fn outbox_videos(
+    outbox: &str,
+    name: &str,
+    seen: &mut HashSet<String>,
+    remaining: u64,
+    ctx: &CrawlCtx,
+) -> Vec<Video> {
+    let max_pages = ctx.limits.max_pages.unwrap_or(ACTIVITYPUB_MAX_PAGES);
+    let fetch = |url: String| async move {
+        get_activity(
+            name,
+            "outbox",
+            &url,
+            ACTIVITY_JSON,
+            ctx.timeouts.videos,
+            true,
+            ctx,
+        )
+        .await
+    };
+    let activities = match walk_collection(outbox, max_pages, fetch).await {
+        Ok(activities) => activities,
+        Err(e) => {
+            endpoint_log!(
+                Level::Trace,
+                name,
+                "outbox",
+                "Failed to walk {} : {}",
+                outbox,
+                e
+            );
+            return vec![];
+        }
+    };
+    let mut videos = vec![];
+    for activity in activities {
+        if videos.len() as u64 >= remaining {
+            break;
+        }
+        // Activities either embed their object or link to it
+        let object = match &activity["object"] {
+            serde_json::Value::String(url) => {
+                if seen.contains(url) {
+                    continue;
+                }
+                match fetch_object(name, url, ctx).await {
+                    Ok(object) => object,
+                    Err(e) => {
+                        endpoint_log!(
+                            Level::Trace,
+                            name,
+                            "object",
+                            "Failed to fetch {} : {}",
+                            url,
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            object => object.clone(),
+        };
+        // Unlisted and private videos are not indexed
+        if object["type"] != "Video" || !is_public(&object) {
+            continue;
+        }
+        if let Some(id) = object_id(&object) {
+            seen.insert(id.to_string());
+        }
+        ctx.video_bar.tick();
+        match video_from_activitypub(&object, name) {
+            Some(video) => {
+                ctx.metrics.videos_parsed.inc();
+                if video.account.host == name && seen.insert(video.uuid.clone()) {
+                    videos.push(video);
+                }
+            }
+            None => {
+                ctx.metrics.videos_failed.inc();
+                host_log!(
+                    Level::Trace,
+                    name,
+                    "Failed to convert ActivityPub video {:?}",
+                    object_id(&object)
+                );
+            }
+        }
+    }
+    videos
+}
+
+/// Crawls an instance through ActivityPub : its instance actor is resolved with WebFinger, the
+/// hosts of its followers and followings are queued, and the videos are read from the outbox of
+/// the instance actor and from the outboxes of the channels found there
+async fn fetch_activitypub(name: String, depth: u32, ctx: CrawlCtx) {
+    let actor = match resolve_instance_actor(&name, &ctx).await {
+        Ok(actor) => actor,
+        Err(e) => {
+            ctx.report
+                .lock()
+                .await
+                .add_error(&name, "activitypub_actor");
+            endpoint_log!(
+                Level::Trace,
+                name,
+                "webfinger",
+                "Failed to resolve the instance actor : {}",
+                e
+            );
+            return;
+        }
+    };
+    ctx.report.lock().await.instance(&name).reachable = true;
 
-    // Request ressources from host
-    let t0 = fetch_follow(
-        "/server/following",
-        "following",
-        name.clone(),
-        ctx.clone(),
-        instance.clone(),
+    let followers = actor["followers"].as_str().unwrap_or_default().to_string();
+    let following = actor["following"].as_str().unwrap_or_default().to_string();
+    let follow = join(
+        fetch_activitypub_follow(&followers, "followers", &name, depth, &ctx),
+        fetch_activitypub_follow(&following, "following", &name, depth, &ctx),
     );
 
-    let t1 = fetch_follow(
-        "/server/followers",
-        "follower",
-        name.clone(),
-        ctx.clone(),
-        instance.clone(),
+    let videos = async {
+        let max_videos = ctx.limits.max_videos.unwrap_or(u64::MAX);
+        let mut seen = HashSet::new();
+        let mut writer: Option<VideoWriter> = None;
+        let mut outboxes: VecDeque<String> = actor["outbox"]
+            .as_str()
+            .map(String::from)
+            .into_iter()
+            .collect();
+        let mut channels = HashSet::new();
+        // Accounts and channels of the videos, None when they could not be fetched
+        let mut actors: HashMap<String, Option<serde_json::Value>> = HashMap::new();
+        let mut fetched: u64 = 0;
+        while let Some(outbox) = outboxes.pop_front() {
+            if fetched >= max_videos || ctx.stopping() {
+                break;
+            }
+            let mut videos =
+                outbox_videos(&outbox, &name, &mut seen, max_videos - fetched, &ctx).await;
+            fetched += videos.len() as u64;
+            ctx.video_bar.inc_length(videos.len() as u64);
+            ctx.video_bar.inc(videos.len() as u64);
+            for video in videos.iter_mut() {
+                for url in &[video.account.url.clone(), video.channel.url.clone()] {
+                    if actors.contains_key(url) {
+                        continue;
+                    }
+                    let actor = match fetch_object(&name, url, &ctx).await {
+                        Ok(actor) => Some(actor),
+                        Err(e) => {
+                            endpoint_log!(
+                                Level::Trace,
+                                name,
+                                "object",
+                                "Failed to fetch {} : {}",
+                                url,
+                                e
+                            );
+                            None
+                        }
+                    };
+                    actors.insert(url.clone(), actor);
+                }
+                let account = actors[&video.account.url].as_ref().and_then(actor_names);
+                if let Some((username, display_name)) = account {
+                    video.account.name = username;
+                    video.account.display_name = display_name;
+                }
+                let channel = &actors[&video.channel.url];
+                if let Some((username, display_name)) = channel.as_ref().and_then(actor_names) {
+                    video.channel.name = username;
+                    video.channel.display_name = display_name;
+                }
+                if channels.insert(video.channel.url.clone()) {
+                    outboxes.extend(
+                        channel
+                            .as_ref()
+                            .and_then(|channel| channel["outbox"].as_str())
+                            .map(String::from),
+                    );
+                }
+            }
+            let videos = select_videos(videos, &ctx).await;
+            ctx.report.lock().await.add_videos(&name, &videos);
+            write_videos(&mut writer, &name, &ctx, &videos).await;
+        }
+        endpoint_log!(
+            Level::Info,
+            name,
+            "outbox",
+            "Fetch complete ({} videos from {} channels)",
+            fetched,
+            channels.len()
+        );
+        finish_videos(writer, &name).await;
+    };
+    join(follow, videos).await;
+}
+
+/// Excludes the servers blocked by an instance from the current round
+async fn import_blocklist(name: String, ctx: CrawlCtx) {
+    let mut blocked = vec![];
+    let mut total: u64 = 1;
+    while (blocked.len() as u64) < total {
+        let url = format!(
+            "https://{}/api/v1/server/blocklist/servers?count=100&start={}",
+            name,
+            blocked.len()
+        );
+        let page = get_json(
+            &name,
+            "/server/blocklist",
+            &url,
+            ctx.timeouts.other,
+            false,
+            &ctx,
+        )
+        .await
+        .ok()
+        .and_then(|json| parse_blocklist_page(&json));
+        match page {
+            Some((hosts, page_total)) if !hosts.is_empty() => {
+                total = page_total;
+                blocked.extend(hosts);
+            }
+            Some(_) => break,
+            None => {
+                host_log!(Level::Trace, name, "Failed to fetch blocklist");
+                return;
+            }
+        }
+    }
+    host_log!(
+        Level::Info,
+        name,
+        "Imported {} blocked servers",
+        blocked.len()
     );
+    let mut filter = ctx.filter.lock().await;
+    for host in blocked {
+        filter.exclude(&host, format!("blocked by {}", name));
+    }
+}
+
+async fn fetch_version(name: String, ctx: CrawlCtx) {
+    let url = format!("https://{}/api/v1/config", name);
+    if let Ok(json) = get_json(&name, "/config", &url, ctx.timeouts.other, false, &ctx).await {
+        if let Some(version) = parse_server_version(&json) {
+            ctx.report.lock().await.instance(&name).version = Some(version);
+        }
+    }
+}
+
+async fn fetch(name: String, depth: u32, ctx: CrawlCtx) {
+    if ctx.stopping() {
+        return;
+    }
+    let instance = Arc::new(Mutex::new(APIInstance::new(name.clone())));
+    let start = Instant::now();
+    ctx.report.lock().await.instance(&name);
+    let pace = ctx.db.lock().await.get_host_pace(&name);
+    ctx.pace.lock().await.insert(name.clone(), pace);
+
+    if ctx.import_blocklists {
+        import_blocklist(name.clone(), ctx.clone()).await;
+    }
+
+    if ctx.fetcher == Fetcher::ActivityPub {
+        join(
+            fetch_activitypub(name.clone(), depth, ctx.clone()),
+            fetch_version(name.clone(), ctx.clone()),
+        )
+        .await;
+    } else {
+        // Request ressources from host
+        let t0 = fetch_follow(
+            "/server/following",
+            "following",
+            name.clone(),
+            depth,
+            ctx.clone(),
+            instance.clone(),
+        );
+
+        let t1 = fetch_follow(
+            "/server/followers",
+            "follower",
+            name.clone(),
+            depth,
+            ctx.clone(),
+            instance.clone(),
+        );
+
+        let t2 = fetch_video(name.clone(), ctx.clone());
 
-    let t2 = fetch_video(name.clone(), ctx.http_client, ctx.video_bar);
+        let t3 = fetch_version(name.clone(), ctx.clone());
 
-    join3(t0, t1, t2).await;
+        let t4 = fetch_remote_hosts(name.clone(), depth, ctx.clone());
+
+        let ((_, _, listed, _), _) = join(join4(t0, t1, t2, t3), t4).await;
+        if !listed && ctx.fetcher == Fetcher::Auto {
+            host_log!(
+                Level::Info,
+                name,
+                "Videos not listed, falling back to ActivityPub"
+            );
+            fetch_activitypub(name.clone(), depth, ctx.clone()).await;
+        }
+    }
+    let mut report = ctx.report.lock().await;
+    let stats = report.instance(&name);
+    stats.elapsed_ms = start.elapsed().as_millis() as u64;
+    if !stats.reachable {
+        ctx.metrics.instances_failed.inc();
+    }
+    let (reachable, videos, latest) = (
+        stats.reachable,
+        stats.videos,
+        stats.latest_published_at.clone(),
+    );
+    drop(report);
+    // Interrupted crawls are made due again by the checkpoint
+    if !ctx.stopping() {
+        let mut db = ctx.db.lock().await;
+        db.record_crawl(&name, reachable, latest.as_deref(), Utc::now());
+        db.set_reputation(&name, reachable, videos);
+    }
+    if let Some(pace) = ctx.pace.lock().await.remove(&name) {
+        ctx.db.lock().await.set_host_pace(&name, &pace);
+    }
+    ctx.metrics.instances_done.inc();
     ctx.instance_bar.inc(1);
-    trace!("[{}] Done", name);
+    host_log!(Level::Trace, name, "Done");
+}
+
+fn create_output_folder() -> PathBuf {
+    create_snapshot(Path::new(OUTPUT_DIR)).expect("Failed to create output dir")
 }
 
-fn create_output_folder() {
-    if !Path::new("./crawled").exists() {
-        std::fs::create_dir("./crawled").expect("Failed to create output dir");
+/// State kept across the crawl rounds of the daemon
+struct Shared {
+    db: Arc<Mutex<InstanceDb>>,
+    /// Operator allow and deny lists, copied into each round so that imported blocklists do not
+    /// carry over
+    filter: InstanceFilter,
+    storage: Sender<Vec<Video>>,
+    metrics: Arc<CrawlMetrics>,
+    /// Set by SIGTERM and SIGINT
+    shutdown: Arc<AtomicBool>,
+}
+
+async fn wait_for_shutdown(shutdown: &AtomicBool) {
+    while !shutdown.load(Ordering::SeqCst) {
+        task::sleep(Duration::from_millis(500)).await;
     }
 }
 
-fn display_cli(mb: Arc<MultiProgress>) {
-    mb.join().unwrap();
+fn build_seed_sources(specs: &[SeedSpec], db: &InstanceDb) -> Vec<Box<dyn SeedSource>> {
+    specs
+        .iter()
+        .map(|spec| -> Box<dyn SeedSource> {
+            match spec {
+                SeedSpec::JoinPeertube => Box::new(JoinPeertube::default()),
+                SeedSpec::FediverseObserver => Box::new(FediverseObserver::default()),
+                SeedSpec::Known => Box::new(HostList {
+                    name: KNOWN_SOURCE.to_string(),
+                    hosts: db.get_all_instances(),
+                }),
+                SeedSpec::File(path) => Box::new(HostFile { path: path.clone() }),
+            }
+        })
+        .collect()
+}
+
+/// Fetches the instances of the seed sources, and records them with their provenance
+async fn collect_and_record_seeds(specs: &[SeedSpec], db: &Mutex<InstanceDb>) -> Vec<String> {
+    let sources = build_seed_sources(specs, &*db.lock().await);
+    let client = HttpClient::new().expect("Failed to create the HTTP client");
+    let seeds = collect_seeds(&sources, &client).await;
+    let mut db = db.lock().await;
+    for (host, sources) in &seeds {
+        // The known instances were recorded along with their provenance when first seen
+        let mut sources = sources.clone();
+        sources.remove(KNOWN_SOURCE);
+        db.insert_seed(host, &sources);
+    }
+    info!(
+        "Loaded {} instances from {} seed sources",
+        seeds.len(),
+        sources.len()
+    );
+    seeds.into_keys().collect()
 }
 
-async fn crawl(root: Option<String>) {
+/// Instances to start a one-shot crawl from : the root if given, else the instances of the seed
+/// sources, by default the known instances and joinpeertube.org
+async fn seed_instances(opt: &Opt, db: &Mutex<InstanceDb>) -> Vec<String> {
+    if let Some(instance) = &opt.root {
+        return vec![instance.clone()];
+    }
+    let specs = if opt.seed_sources.is_empty() {
+        vec![SeedSpec::Known, SeedSpec::JoinPeertube]
+    } else {
+        opt.seed_sources.clone()
+    };
+    collect_and_record_seeds(&specs, db).await
+}
+
+/// Keeps only the operator deny list blocks in the database, so that the instances excluded by a
+/// previous round are checked again
+fn reset_blocked_instances(db: &mut InstanceDb, filter: &InstanceFilter) {
+    db.refresh_blocked_instances(|host| match filter.check(host) {
+        Verdict::Denied(reason) => Some(reason),
+        _ => None,
+    });
+}
+
+/// Crawls the given instances into the snapshot `output_dir`, along with the instances they lead
+/// to when `recursive` is set. On shutdown, the fetches stop between two pages and the unfinished
+/// instances are left due in the database so that the next run resumes them.
+async fn crawl(
+    opt: &Opt,
+    instances: Vec<String>,
+    recursive: bool,
+    output_dir: &Path,
+    shared: &Shared,
+) {
     // TODO : use SegQueue
     let nodes = Arc::new(Mutex::new(HashSet::new()));
-    let result: Arc<Mutex<HashSet<APIInstance>>> = Arc::new(Mutex::new(HashSet::new()));
     let count = Arc::new(Mutex::new(0));
-    create_output_folder();
-    let mb = Arc::new(MultiProgress::new());
-    let instance_bar = mb.add(ProgressBar::new(0));
-    let video_bar = mb.add(ProgressBar::new(0));
-    video_bar.set_prefix("Fetching videos :");
-    instance_bar.set_prefix("Fetching neighbours :");
-    let sty = ProgressStyle::default_bar()
-        .template("{prefix} [{wide_bar:.cyan/blue}] {per_sec} {pos:>7}/{len:7}({percent}%) {eta} remaining")
-        .progress_chars("=>-");
-    instance_bar.set_style(sty.clone());
-    video_bar.set_style(sty.clone());
-    video_bar.tick();
-    instance_bar.tick();
-    let mb_clone = mb.clone();
-
-    // Handle startup logic : either we received a root to start from, or we fetch joinpeertube.org
-    let mut instances = vec![];
-    let instance_db = Arc::new(Mutex::new(InstanceDb::new()));
-    if let Some(instance) = root {
-        instances.push(instance);
-    } else {
-        instances = instance_db.lock().await.get_all_instances();
-    }
-    for instance in &instances[0..min(LIMIT as usize, instances.len())] {
-        nodes.lock().await.insert(instance.clone());
-    }
-    instance_bar.set_length(instances.len().try_into().unwrap());
-    instance_bar.println(format!("Loaded {} instances", instances.len()));
-    if nodes.lock().await.len() == 0 {
-        info!("No instance found in the database, seeding from https://instances.joinpeertube.org");
-        match fetch_instance_list_from_joinpeertube() {
-            Ok(res) => {
-                instance_bar.set_length(res.len().try_into().unwrap());
-                instance_bar.println(format!("Fetched {} instances", res.len()));
-                for s in res {
-                    instances.push(s);
-                }
-            }
-            Err(e) => warn!(
-                "Failed to retrieve instances from https://instances.joinpeertube.org : {}",
-                e
-            ),
-        }
-    }
+    let progress = Progress::new(opt.progress);
+    let instance_bar = progress.instances.clone();
+    let video_bar = progress.videos.clone();
     info!(
         "Starting crawling process from {} instances",
         instances.len()
     );
     let start = Instant::now();
+    let round_start = Utc::now();
 
+    let timeouts = Timeouts {
+        connect: Duration::from_secs(opt.connect_timeout),
+        follow: Duration::from_secs(opt.follow_timeout),
+        videos: Duration::from_secs(opt.videos_timeout),
+        max: Duration::from_secs(opt.max_timeout),
+        ..Timeouts::default()
+    };
     let client = HttpClient::builder()
-        .timeout(Duration::from_secs(10))
-        .connect_timeout(Duration::from_secs(10))
+        .connect_timeout(timeouts.connect)
         .connection_cache_size(4096 * 100_000_000) /* 100 MB cache */
         .build()
         .unwrap();
 
-    std::thread::spawn(move || {
-        display_cli(mb_clone);
-    });
+    progress.start();
 
-    crawl_from_instances(
-        instances,
-        nodes.clone(),
-        result.clone(),
+    let policy_stats = Arc::new(Mutex::new(PolicyStats::default()));
+    let report = Arc::new(Mutex::new(CrawlReport::new(Local::now().to_rfc3339())));
+    let ctx = CrawlCtx {
+        nodes: nodes.clone(),
         count,
-        instance_db.clone(),
-        Arc::new(client),
-        instance_bar.clone(),
-        video_bar.clone(),
-    )
-    .await;
-    instance_bar.finish_with_message(&format!("Found {} instances", nodes.lock().await.len()));
-    video_bar.finish_with_message("Fetched all videos");
+        db: shared.db.clone(),
+        filter: Arc::new(Mutex::new(shared.filter.clone())),
+        import_blocklists: opt.import_blocklists,
+        fetcher: opt.fetcher,
+        recursive,
+        limits: CrawlLimits {
+            max_videos: opt.max_videos,
+            max_pages: opt.max_pages,
+            max_instances: opt.max_instances,
+            max_depth: opt.max_depth,
+        },
+        discover_from_videos: opt.discover_from_videos,
+        discovery_pages: opt.discovery_pages,
+        sampler: opt
+            .sample
+            .map(|rate| Arc::new(Mutex::new(Sampler::new(rate, opt.sample_seed)))),
+        policy: VideoPolicy::new(opt.nsfw_policy),
+        policy_stats: policy_stats.clone(),
+        http_client: Arc::new(client),
+        retry_policy: RetryPolicy {
+            max_retries: opt.max_retries,
+            ..RetryPolicy::default()
+        },
+        timeouts,
+        pace: Arc::new(Mutex::new(HashMap::new())),
+        output_dir: output_dir.to_path_buf(),
+        compression: opt.compression,
+        storage: shared.storage.clone(),
+        report: report.clone(),
+        metrics: shared.metrics.clone(),
+        instance_bar: instance_bar.clone(),
+        video_bar: video_bar.clone(),
+        shutdown: shared.shutdown.clone(),
+    };
+    let seeds = instances.clone();
+    crawl_from_instances(instances, ctx).await;
+    if shared.shutdown.load(Ordering::SeqCst) {
+        let pending: Vec<String> = nodes.lock().await.iter().cloned().chain(seeds).collect();
+        shared.db.lock().await.checkpoint(&pending, round_start);
+        warn!("Crawl interrupted, unfinished instances will be crawled on the next run");
+    }
+    progress.finish(
+        &format!("Found {} instances", nodes.lock().await.len()),
+        "Fetched all videos",
+    );
     let duration = start.elapsed();
     info!(
         "Added {} instances in {} seconds",
         (*nodes.lock().await).len(),
         duration.as_secs()
     );
+    let policy_stats = policy_stats.lock().await;
+    info!(
+        "Videos : {} indexed, {} hidden (NSFW), {} skipped (NSFW), {} skipped (blacklisted)",
+        policy_stats.indexed,
+        policy_stats.hidden,
+        policy_stats.skipped_nsfw,
+        policy_stats.skipped_blacklisted
+    );
+    let mut report = report.lock().await;
+    let discovered = nodes
+        .lock()
+        .await
+        .iter()
+        .chain(report.instances.keys())
+        .collect::<HashSet<&String>>()
+        .len();
+    report.finish(discovered as u64, duration.as_secs());
+    info!(
+        "{} of {} instances reachable, {} videos",
+        report.summary.instances_reachable,
+        report.summary.instances_discovered,
+        report.summary.videos
+    );
+    if let Err(e) = report.write(output_dir, Path::new(OUTPUT_DIR)) {
+        error!("Failed to write the crawl report : {}", e);
+    }
+    match rotate_snapshots(Path::new(OUTPUT_DIR), opt.keep_snapshots) {
+        Ok(removed) => {
+            for dir in removed {
+                info!("Removed old snapshot {:?}", dir);
+            }
+        }
+        Err(e) => warn!("Failed to rotate snapshots : {}", e),
+    }
 }
 
-fn elastic_is_online(client: HttpClient) -> bool {
-    if let Err(e) = create_mappings("http://localhost:9200".to_string(), client) {
-        error!("{}", e);
-        false
+/// Adds the instances of the seed sources to the database, at most once a day, or hourly while
+/// every source fails. Nothing is done when only the known instances are used as seeds.
+async fn reseed(opt: &Opt, db: &Mutex<InstanceDb>, now: DateTime<Utc>) {
+    // The known instances are already scheduled
+    let specs: Vec<SeedSpec> = if opt.seed_sources.is_empty() {
+        vec![SeedSpec::JoinPeertube]
     } else {
-        info!("Sucessfully initialized elastic search");
-        true
+        opt.seed_sources
+            .iter()
+            .filter(|spec| **spec != SeedSpec::Known)
+            .cloned()
+            .collect()
+    };
+    if specs.is_empty() {
+        return;
+    }
+    let within = |key: &str, hours: i64, db: &InstanceDb| {
+        db.get_state(key)
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .is_some_and(|date| now - date.with_timezone(&Utc) < chrono::Duration::hours(hours))
+    };
+    {
+        let mut db = db.lock().await;
+        if within(LAST_SEED_KEY, RESEED_INTERVAL_HOURS, &db)
+            || within(LAST_SEED_ATTEMPT_KEY, RESEED_RETRY_HOURS, &db)
+        {
+            return;
+        }
+        db.set_state(LAST_SEED_ATTEMPT_KEY, &now.to_rfc3339());
+    }
+    if collect_and_record_seeds(&specs, db).await.is_empty() {
+        warn!(
+            "No seed source answered, trying again in {} hours",
+            RESEED_RETRY_HOURS
+        );
+    } else {
+        db.lock().await.set_state(LAST_SEED_KEY, &now.to_rfc3339());
+    }
+}
+
+/// Recrawls the instances whenever they are due, until a shutdown is requested. The rounds all
+/// write to the same snapshot, which starts with the crawl files of the previous snapshot, so that
+/// it always holds every host.
+async fn run_daemon(opt: &Opt, shared: &Shared) {
+    if let Some(root) = &opt.root {
+        shared.db.lock().await.insert_instance(root.clone());
+    }
+    let previous = latest_snapshot(Path::new(OUTPUT_DIR)).ok();
+    let output_dir = create_output_folder();
+    if let Some(previous) = previous {
+        match link_crawl_files(&previous, &output_dir) {
+            Ok(count) => info!("Carried {} crawl files over from {:?}", count, previous),
+            Err(e) => warn!(
+                "Failed to carry crawl files over from {:?} : {}",
+                previous, e
+            ),
+        }
+    }
+    while !shared.shutdown.load(Ordering::SeqCst) {
+        let now = Utc::now();
+        reset_blocked_instances(&mut *shared.db.lock().await, &shared.filter);
+        reseed(opt, &shared.db, now).await;
+        let due = shared.db.lock().await.get_due_instances(now);
+        if due.is_empty() {
+            select(
+                Box::pin(task::sleep(Duration::from_secs(DAEMON_POLL_SECS))),
+                Box::pin(wait_for_shutdown(&shared.shutdown)),
+            )
+            .await;
+        } else {
+            info!("Recrawling {} due instances", due.len());
+            crawl(opt, due, false, &output_dir, shared).await;
+        }
+    }
+    info!("Daemon stopped");
+}
+
+fn open_video_storage(kind: StorageKind) -> Option<Box<dyn VideoStorage>> {
+    match open_storage(kind, &EsConfig::from_env()) {
+        Ok(storage) => {
+            info!("Sucessfully initialized {:?} storage", kind);
+            Some(storage)
+        }
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Stores the pages of videos received until every sender is dropped, grouping them in batches so
+/// that the crawl never waits for each write
+fn store_videos(mut storage: Box<dyn VideoStorage>, pages: Receiver<Vec<Video>>) {
+    while let Ok(mut batch) = block_on(pages.recv()) {
+        while batch.len() < STORE_BATCH_SIZE {
+            match pages.try_recv() {
+                Ok(page) => batch.extend(page),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = storage.store_videos(&batch) {
+            error!("Failed to store {} videos : {}", batch.len(), e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, expected text or json", s)),
+        }
     }
 }
 
@@ -460,30 +1677,204 @@ struct Opt {
     /// Timestamp (sec, ms, ns, none)
     #[structopt(short = "t", long = "timestamp")]
     ts: Option<stderrlog::Timestamp>,
+    /// Log format : text or json (one object per line, with host and endpoint fields)
+    #[structopt(long = "log-format", default_value = "text")]
+    log_format: LogFormat,
+    /// How to show the progress : bars, log (periodic log lines), json (periodic events on
+    /// stderr) or none
+    #[structopt(long = "progress", default_value = "bars")]
+    progress: ProgressMode,
 
     /// Root domain name
-    /// Uses joinpeertube.org if missing
+    /// Uses the seed sources if missing
     #[structopt(short = "r", long = "root")]
     root: Option<String>,
+
+    /// Where to find the instances to start from : joinpeertube, fediverse-observer, known
+    /// (instances of previous crawls) or file:<path> (one host per line). Can be repeated.
+    /// Defaults to known and joinpeertube.
+    #[structopt(long = "seed-source")]
+    seed_sources: Vec<SeedSpec>,
+
+    /// File of host patterns to restrict the crawl to, one per line (`*` is a wildcard)
+    #[structopt(long = "allowlist", parse(from_os_str))]
+    allowlist: Option<PathBuf>,
+
+    /// File of host patterns to exclude, one per line, optionally followed by a reason
+    #[structopt(long = "denylist", parse(from_os_str))]
+    denylist: Option<PathBuf>,
+
+    /// Also exclude the servers blocked by the crawled instances, for the current run only
+    #[structopt(long = "import-blocklists")]
+    import_blocklists: bool,
+
+    /// What to do with NSFW videos : skip, hide (index but hide from default searches) or index
+    #[structopt(long = "nsfw-policy", default_value = "hide")]
+    nsfw_policy: NsfwPolicy,
+
+    /// Compression of the crawl files : none, gzip or zstd
+    #[structopt(long = "compression", default_value = "none")]
+    compression: Compression,
+
+    /// Number of crawl snapshots to keep in the output directory (at least 1)
+    #[structopt(long = "keep-snapshots", default_value = "5")]
+    keep_snapshots: usize,
+
+    /// Where to store the videos : elastic or sqlite
+    #[structopt(long = "storage", default_value = "elastic")]
+    storage: StorageKind,
+
+    /// Maximum number of videos fetched from each instance
+    #[structopt(long = "max-videos")]
+    max_videos: Option<u64>,
+
+    /// Maximum number of pages of videos fetched from each instance
+    #[structopt(long = "max-pages")]
+    max_pages: Option<u64>,
+
+    /// Maximum number of instances crawled
+    #[structopt(long = "max-instances")]
+    max_instances: Option<u64>,
+
+    /// Maximum number of hops from the seed instances, which are at depth 0
+    #[structopt(long = "max-depth")]
+    max_depth: Option<u32>,
+
+    /// Crawls a random sample of the instances, each one being kept with this probability
+    /// (e.g. 0.1)
+    #[structopt(long = "sample", parse(try_from_str = "parse_sample_rate"))]
+    sample: Option<f64>,
+
+    /// Seed of the sampling, to crawl the same sample again
+    #[structopt(long = "sample-seed")]
+    sample_seed: Option<u64>,
+
+    /// How instances are read : rest (PeerTube API), activitypub (WebFinger, then the collections
+    /// and outboxes of the instance actor) or auto (rest, falling back to activitypub for
+    /// instances not listing their videos)
+    #[structopt(long = "fetcher", default_value = "rest")]
+    fetcher: Fetcher,
+
+    /// Also discover instances from the origin of the federated videos shown by each instance
+    #[structopt(long = "discover-from-videos")]
+    discover_from_videos: bool,
+
+    /// Maximum number of pages of federated videos fetched from each instance when discovering
+    #[structopt(long = "discovery-pages", default_value = "10")]
+    discovery_pages: u64,
+
+    /// Timeout of the connection to an instance, in seconds
+    #[structopt(long = "connect-timeout", default_value = "10")]
+    connect_timeout: u64,
+
+    /// Timeout of the follower and following requests, in seconds
+    #[structopt(long = "follow-timeout", default_value = "20")]
+    follow_timeout: u64,
+
+    /// Timeout of the video requests, in seconds
+    #[structopt(long = "videos-timeout", default_value = "60")]
+    videos_timeout: u64,
+
+    /// Limit of the timeouts, which are raised for slow instances, in seconds
+    #[structopt(long = "max-timeout", default_value = "300")]
+    max_timeout: u64,
+
+    /// Number of retries of a request failing with a transient error (timeout, HTTP 429 or 503...)
+    #[structopt(long = "max-retries", default_value = "3")]
+    max_retries: u32,
+
+    /// Keep running, recrawling each instance on a schedule based on its activity (hourly for
+    /// busy instances, weekly for dormant ones), and reseeding from the seed sources daily
+    #[structopt(long = "daemon")]
+    daemon: bool,
+
+    /// Address to serve Prometheus metrics on, at `/metrics` (e.g. 127.0.0.1:9898)
+    #[structopt(long = "metrics")]
+    metrics_addr: Option<String>,
+}
+
+fn load_filter(opt: &Opt) -> Result<InstanceFilter, Box<dyn std::error::Error>> {
+    let mut filter = InstanceFilter::new();
+    if let Some(path) = &opt.allowlist {
+        filter.load_allowlist(path)?;
+    }
+    if let Some(path) = &opt.denylist {
+        filter.load_denylist(path)?;
+    }
+    Ok(filter)
+}
+
+fn init_logger(opt: &Opt) {
+    let modules = [module_path!(), "peertube_lib"];
+    if opt.log_format == LogFormat::Json {
+        let level = match (opt.quiet, opt.verbose) {
+            (true, _) => LevelFilter::Off,
+            (false, 0) => LevelFilter::Error,
+            (false, 1) => LevelFilter::Warn,
+            (false, 2) => LevelFilter::Info,
+            (false, 3) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        };
+        JsonLogger::init(level, &modules).unwrap();
+    } else {
+        stderrlog::new()
+            .modules(modules.iter().cloned())
+            .quiet(opt.quiet)
+            .verbosity(opt.verbose)
+            .timestamp(opt.ts.unwrap_or(stderrlog::Timestamp::Off))
+            .color(if opt.progress == ProgressMode::Bars {
+                ColorChoice::Always
+            } else {
+                ColorChoice::Auto
+            })
+            .init()
+            .unwrap();
+    }
 }
 
 fn main() -> Result<(), ()> {
     let opt = Opt::from_args();
-    let client = isahc::HttpClient::new().unwrap();
-    stderrlog::new()
-        .module(module_path!())
-        .quiet(opt.quiet)
-        .verbosity(opt.verbose)
-        .timestamp(opt.ts.unwrap_or(stderrlog::Timestamp::Off))
-        .color(ColorChoice::Always)
-        .init()
-        .unwrap();
+    init_logger(&opt);
     info!("Starting crawler");
-    if elastic_is_online(client) {
-        block_on(crawl(opt.root));
-        Ok(())
-    } else {
-        error!("Failed to connect to elastic instance");
-        Err(())
+    let filter = load_filter(&opt).map_err(|e| error!("Failed to load host lists : {}", e))?;
+    let storage = open_video_storage(opt.storage).ok_or_else(|| {
+        error!("Failed to open the video storage");
+    })?;
+    let metrics = Arc::new(CrawlMetrics::new().expect("Failed to register the metrics"));
+    if let Some(addr) = &opt.metrics_addr {
+        match serve_metrics(addr, metrics.clone()) {
+            Ok(()) => info!("Serving metrics on http://{}/metrics", addr),
+            Err(e) => error!("Failed to serve metrics on {} : {}", addr, e),
+        }
     }
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in &[SIGTERM, SIGINT] {
+        signal_hook::flag::register(*signal, shutdown.clone())
+            .map_err(|e| error!("Failed to handle signals : {}", e))?;
+    }
+    let mut db = InstanceDb::new();
+    reset_blocked_instances(&mut db, &filter);
+    let (sender, pages) = bounded(STORE_QUEUE_SIZE);
+    let storage_thread = std::thread::spawn(move || store_videos(storage, pages));
+    let shared = Shared {
+        db: Arc::new(Mutex::new(db)),
+        filter,
+        storage: sender,
+        metrics,
+        shutdown,
+    };
+    block_on(async {
+        if opt.daemon {
+            run_daemon(&opt, &shared).await;
+        } else {
+            let instances = seed_instances(&opt, &shared.db).await;
+            crawl(&opt, instances, true, &create_output_folder(), &shared).await;
+        }
+    });
+    // Waits for the videos still queued to be stored
+    drop(shared);
+    storage_thread
+        .join()
+        .map_err(|_| error!("The storage thread panicked"))?;
+    Ok(())
 }
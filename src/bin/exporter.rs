@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use peertube_lib::crawl_output::{
+    crawl_file_host, crawl_files, latest_snapshot, list_snapshots, open_crawl_file, snapshot_date,
+};
+use peertube_lib::export::{Format, Partition, RecordsWriter, VideoRecord};
+use peertube_lib::peertube_api::Video;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+const DIR: &str = "./crawled";
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+struct Opt {
+    /// Output formats (parquet, csv)
+    #[structopt(short = "f", long = "format", default_value = "parquet")]
+    formats: Vec<Format>,
+
+    /// Partitioning of the output (none, host, date)
+    #[structopt(short = "p", long = "partition", default_value = "none")]
+    partition: Partition,
+
+    /// Output directory
+    #[structopt(
+        short = "o",
+        long = "output",
+        default_value = "export",
+        parse(from_os_str)
+    )]
+    output: PathBuf,
+
+    /// Export every snapshot instead of the most recent one
+    #[structopt(long = "all-snapshots")]
+    all_snapshots: bool,
+
+    /// Snapshot directory to export
+    /// Uses the most recent snapshot if missing
+    #[structopt(parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+}
+
+/// A crawl file, with the host and the day it was crawled
+struct CrawlFile {
+    path: PathBuf,
+    host: String,
+    crawl_date: String,
+}
+
+/// Lists the crawl files of a snapshot. Files of crawls made before snapshots existed are dated
+/// from their modification time.
+fn snapshot_files(snapshot: &Path) -> Result<Vec<CrawlFile>, Box<dyn Error>> {
+    let date = snapshot_date(snapshot).map(|date| date.format("%Y-%m-%d").to_string());
+    let mut files = vec![];
+    for path in crawl_files(snapshot)? {
+        let crawl_date = match &date {
+            Some(date) => date.clone(),
+            None => DateTime::<Utc>::from(fs::metadata(&path)?.modified()?)
+                .format("%Y-%m-%d")
+                .to_string(),
+        };
+        files.push(CrawlFile {
+            host: crawl_file_host(&path).unwrap_or_default(),
+            path,
+            crawl_date,
+        });
+    }
+    Ok(files)
+}
+
+fn read_records(file: &CrawlFile) -> Result<Vec<VideoRecord>, Box<dyn Error>> {
+    let mut records = vec![];
+    for line in open_crawl_file(&file.path)?.lines() {
+        match serde_json::from_str::<Video>(&line?) {
+            Ok(video) => records.push(VideoRecord::new(&video, &file.host, &file.crawl_date)),
+            Err(e) => eprintln!("Skipping malformed video in {:?} : {}", file.path, e),
+        }
+    }
+    Ok(records)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let snapshots = match opt.snapshot {
+        Some(dir) => vec![dir],
+        None if opt.all_snapshots => list_snapshots(Path::new(DIR))?,
+        None => vec![latest_snapshot(Path::new(DIR))?],
+    };
+    let mut partitions: BTreeMap<Option<String>, Vec<CrawlFile>> = BTreeMap::new();
+    for snapshot in &snapshots {
+        println!("Reading {}", snapshot.to_str().unwrap());
+        for file in snapshot_files(snapshot)? {
+            partitions
+                .entry(opt.partition.directory(&file.host, &file.crawl_date))
+                .or_default()
+                .push(file);
+        }
+    }
+
+    // Records are streamed one crawl file at a time, each partition being written in turn
+    for (directory, files) in &partitions {
+        let dir = match directory {
+            Some(directory) => opt.output.join(directory),
+            None => opt.output.clone(),
+        };
+        fs::create_dir_all(&dir)?;
+        let mut writers = vec![];
+        for format in &opt.formats {
+            let path = dir.join(format!("videos.{}", format.extension()));
+            writers.push((RecordsWriter::create(&path, *format)?, path));
+        }
+        for file in files {
+            let records = read_records(file)?;
+            for (writer, _) in &mut writers {
+                writer.write(&records)?;
+            }
+        }
+        for (writer, path) in writers {
+            let written = writer.written();
+            writer.close()?;
+            println!("Exported {} videos to {}", written, path.to_str().unwrap());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use peertube_lib::crawl_output::{crawl_files, latest_snapshot, open_crawl_file};
+use peertube_lib::elastic::{Elastic, EsConfig};
+use peertube_lib::peertube_api::Video;
+use peertube_lib::video_storage::{open_storage, StorageKind};
+use std::error::Error;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+const DIR: &str = "./crawled";
+
+/** Number of videos sent to the storage at once */
+const BATCH_SIZE: usize = 500;
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+struct Opt {
+    /// Where to store the videos : elastic or sqlite
+    #[structopt(long = "storage", default_value = "elastic")]
+    storage: StorageKind,
+
+    /// Instead of indexing a snapshot, copies the Elastic index into a new index created from
+    /// the current mappings, and moves the `peertube_se` alias to it once the counts match. The
+    /// index is read-only meanwhile, so the crawler must be stopped first.
+    #[structopt(long = "reindex")]
+    reindex: bool,
+
+    /// Instead of indexing a snapshot, prints the version and health of the Elastic cluster, and
+    /// the differences between the mappings of the index and es_mappings.json
+    #[structopt(long = "check")]
+    check: bool,
+
+    /// Snapshot directory to index
+    /// Uses the most recent snapshot if missing
+    #[structopt(parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let config = EsConfig::from_env();
+    if opt.check {
+        let elastic = Elastic::new(&config)?;
+        let health = elastic.health()?;
+        println!(
+            "Elastic Search {} at {} is {}",
+            health.version, config.addr, health.status
+        );
+        let diff = elastic.mapping_diff()?;
+        if diff.is_empty() {
+            println!("The mappings are up to date");
+        }
+        for field in &diff.missing {
+            println!("missing : {}", field);
+        }
+        for field in &diff.changed {
+            println!("changed : {}", field);
+        }
+        for field in &diff.extra {
+            println!("extra : {}", field);
+        }
+        return Ok(());
+    }
+    if opt.reindex {
+        let summary = Elastic::new(&config)?.reindex()?;
+        println!(
+            "Reindexed {} videos from {} into {}, now behind the alias",
+            summary.videos, summary.source, summary.target
+        );
+        return Ok(());
+    }
+    let snapshot = match opt.snapshot {
+        Some(dir) => dir,
+        None => latest_snapshot(Path::new(DIR))?,
+    };
+    let mut storage = open_storage(opt.storage, &config)?;
+    let mut total = 0;
+    for file in crawl_files(&snapshot)? {
+        let mut batch: Vec<Video> = vec![];
+        for line in open_crawl_file(&file)?.lines() {
+            batch.push(serde_json::from_str(&line?)?);
+            if batch.len() == BATCH_SIZE {
+                storage.store_videos(&batch)?;
+                total += batch.len();
+                batch.clear();
+            }
+        }
+        storage.store_videos(&batch)?;
+        total += batch.len();
+        println!("Indexed {}", file.to_str().unwrap());
+    }
+    println!("Indexed {} videos into {:?} storage", total, opt.storage);
+    Ok(())
+}
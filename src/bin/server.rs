@@ -0,0 +1,498 @@
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext};
+use log::*;
+use peertube_lib::elastic::EsConfig;
+use peertube_lib::instance_storage::InstanceDb;
+use peertube_lib::peertube_api::Video;
+use peertube_lib::ranking::{InstanceReputation, Ranking, WeightsFile};
+use peertube_lib::search::SearchQuery;
+use peertube_lib::thumbnails::{ThumbnailCache, ThumbnailFetcher, ThumbnailSize};
+use peertube_lib::video_storage::{open_storage, StorageKind, VideoStorage};
+use peertube_lib::web::{
+    content_type, format_duration, instance_url, is_valid_id, parse_url, percent_encode,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use tiny_http::{Header, Request, Response, Server};
+
+const TEMPLATES_DIR: &str = "templates";
+
+const STATIC_DIR: &str = "static";
+
+const RESULTS_PER_PAGE: u64 = 20;
+
+const SUGGESTIONS: u64 = 8;
+
+/** Number of related videos, and of other videos of the channel, shown on video pages */
+const RELATED_VIDEOS: u64 = 6;
+
+/** Shorter texts are not completed, as they match too many videos */
+const SUGGEST_MIN_LENGTH: usize = 2;
+
+/** Delay during which browsers reuse a thumbnail, in seconds */
+const THUMBNAIL_MAX_AGE: u64 = 7 * 24 * 3600;
+
+/** Image shown for the thumbnails that could not be fetched, or are being fetched */
+const PLACEHOLDER: &str = "placeholder.svg";
+
+/** Threads downloading the thumbnails missing from the cache */
+const THUMBNAIL_THREADS: usize = 4;
+
+/** Delay before the instance reputations are read again from the database */
+const REPUTATION_REFRESH: Duration = Duration::from_secs(600);
+
+/// Link to the page of a video on its instance, from its crawled host and uuid
+fn instance_page(host: &str, prefix: &str, uuid: &str) -> Option<String> {
+    if is_valid_id(uuid) {
+        instance_url(host, &format!("{}{}", prefix, uuid))
+    } else {
+        None
+    }
+}
+
+/// A search result, as shown by the templates
+#[derive(Serialize)]
+struct VideoView {
+    name: String,
+    /// Page of the video on this server
+    url: String,
+    /// Page of the video on its instance, None when its crawled host or uuid are invalid
+    origin_url: Option<String>,
+    thumbnail: String,
+    description: String,
+    creator: String,
+    host: String,
+    views: i64,
+    likes: i64,
+}
+
+impl VideoView {
+    fn new(video: &Video) -> VideoView {
+        let host = &video.account.host;
+        VideoView {
+            name: video.name.clone(),
+            url: format!("/video/{}", percent_encode(&video.uuid)),
+            origin_url: instance_page(host, "/videos/watch/", &video.uuid),
+            thumbnail: format!("/thumbnails/{}.jpg", percent_encode(&video.uuid)),
+            description: video.description.clone().unwrap_or_default(),
+            creator: video.account.display_name.clone(),
+            host: host.clone(),
+            views: video.views,
+            likes: video.likes,
+        }
+    }
+}
+
+/// A tag of a video, linking to its search
+#[derive(Serialize)]
+struct TagView {
+    name: String,
+    url: String,
+}
+
+/// A video page, with every metadata of the video
+#[derive(Serialize)]
+struct DetailView {
+    #[serde(flatten)]
+    summary: VideoView,
+    embed_url: Option<String>,
+    preview: String,
+    duration: String,
+    /// Publication date, without the time
+    published: String,
+    category: String,
+    licence: String,
+    language: String,
+    tags: Vec<TagView>,
+    dislikes: i64,
+    nsfw: bool,
+    account_url: Option<String>,
+    channel: String,
+    channel_url: Option<String>,
+}
+
+impl DetailView {
+    fn new(video: &Video) -> DetailView {
+        let host = &video.account.host;
+        DetailView {
+            summary: VideoView::new(video),
+            embed_url: instance_page(host, "/videos/embed/", &video.uuid),
+            preview: format!("/previews/{}.jpg", percent_encode(&video.uuid)),
+            duration: format_duration(video.duration),
+            published: video
+                .published_at
+                .split('T')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            category: video.category.label.clone(),
+            licence: video.licence.label.clone(),
+            language: video.language.label.clone(),
+            tags: video
+                .tags
+                .iter()
+                .map(|tag| TagView {
+                    name: tag.clone(),
+                    url: format!("/search?query={}", percent_encode(tag)),
+                })
+                .collect(),
+            dislikes: video.dislikes,
+            nsfw: video.nsfw,
+            // Built rather than taken from the crawled URLs, which could hold any scheme
+            account_url: instance_url(
+                host,
+                &format!("/accounts/{}", percent_encode(&video.account.name)),
+            ),
+            channel: video.channel.display_name.clone(),
+            channel_url: instance_url(
+                &video.channel.host,
+                &format!("/video-channels/{}", percent_encode(&video.channel.name)),
+            ),
+        }
+    }
+}
+
+/// Resolves `{{> (parent)}}` in the page templates to the layout named by the `parent` field
+fn parent(
+    _: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(ctx.data()["parent"].as_str().unwrap_or("layout"))?;
+    Ok(())
+}
+
+/// Registers `templates/<name>.html.hbs` under `<name>`
+fn load_templates(dir: &Path) -> Result<Handlebars<'static>, Box<dyn Error>> {
+    let mut templates = Handlebars::new();
+    templates.register_helper("parent", Box::new(parent));
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(name) = file_name.strip_suffix(".html.hbs") {
+            templates.register_template_file(name, &path)?;
+        }
+    }
+    Ok(templates)
+}
+
+struct SearchServer {
+    storage: Box<dyn VideoStorage>,
+    thumbnails: ThumbnailFetcher,
+    templates: Handlebars<'static>,
+    /// Ranking weights, None to rank by text relevance only
+    weights: Option<WeightsFile>,
+    reputations: Vec<InstanceReputation>,
+    reputations_loaded: Option<Instant>,
+}
+
+impl SearchServer {
+    fn reputations(&mut self) -> Vec<InstanceReputation> {
+        if self
+            .reputations_loaded
+            .is_none_or(|loaded| loaded.elapsed() > REPUTATION_REFRESH)
+        {
+            // The last reputations are kept while the crawler database is unavailable
+            match InstanceDb::open().and_then(|db| db.get_reputations()) {
+                Ok(reputations) => self.reputations = reputations,
+                Err(e) => warn!("Failed to load the instance reputations : {}", e),
+            }
+            self.reputations_loaded = Some(Instant::now());
+        }
+        self.reputations.clone()
+    }
+
+    fn search(&mut self, params: &HashMap<String, String>) -> Result<String, Box<dyn Error>> {
+        let text = params.get("query").cloned().unwrap_or_default();
+        let page: u64 = params
+            .get("page")
+            .and_then(|page| page.parse().ok())
+            .unwrap_or(1)
+            .max(1);
+        let mut query = SearchQuery::new(text.clone());
+        query.nsfw = params.get("nsfw").is_some_and(|nsfw| nsfw == "1");
+        query.from = (page - 1) * RESULTS_PER_PAGE;
+        query.size = RESULTS_PER_PAGE;
+        if let Some(weights) = &mut self.weights {
+            let weights = weights.weights().clone();
+            query.ranking = Some(Ranking {
+                weights,
+                reputations: self.reputations(),
+            });
+        }
+        let videos = self.storage.search(&query)?;
+        let next_url = if videos.len() as u64 == RESULTS_PER_PAGE {
+            Some(format!(
+                "/search?query={}&page={}{}",
+                percent_encode(&text),
+                page + 1,
+                if query.nsfw { "&nsfw=1" } else { "" }
+            ))
+        } else {
+            None
+        };
+        let videos: Vec<VideoView> = videos.iter().map(VideoView::new).collect();
+        Ok(self.templates.render(
+            "video",
+            &json!({
+                "parent": "layout",
+                "query": text,
+                "videos": videos,
+                "next_url": next_url
+            }),
+        )?)
+    }
+
+    /// Renders the page of a video, with the videos related to it and the other videos of its
+    /// channel. Returns None for unknown videos.
+    fn video_page(
+        &self,
+        uuid: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        if !is_valid_id(uuid) {
+            return Ok(None);
+        }
+        let video = match self.storage.get_video(uuid)? {
+            Some(video) => video,
+            None => return Ok(None),
+        };
+        let nsfw = params.get("nsfw").is_some_and(|nsfw| nsfw == "1");
+        let views =
+            |videos: Vec<Video>| -> Vec<VideoView> { videos.iter().map(VideoView::new).collect() };
+        // A page is still useful without its suggestions
+        let related = self
+            .storage
+            .related(&video, nsfw, RELATED_VIDEOS)
+            .unwrap_or_else(|e| {
+                warn!("Failed to find the videos related to {} : {}", uuid, e);
+                vec![]
+            });
+        let channel_videos = self
+            .storage
+            .channel_videos(&video, nsfw, RELATED_VIDEOS)
+            .unwrap_or_else(|e| {
+                warn!("Failed to list the channel of {} : {}", uuid, e);
+                vec![]
+            });
+        Ok(Some(self.templates.render(
+            "watch",
+            &json!({
+                "parent": "layout",
+                "video": DetailView::new(&video),
+                "related": views(related),
+                "channel_videos": views(channel_videos)
+            }),
+        )?))
+    }
+
+    /// Answers `<uuid>.jpg` with the thumbnail of the video, letting browsers keep it. Thumbnails
+    /// missing from the cache get a placeholder while they are fetched from the instance of the
+    /// video in the background.
+    fn thumbnail_reply(
+        &self,
+        file: &str,
+        size: ThumbnailSize,
+        max_age: &mut Option<u64>,
+    ) -> (u16, &'static str, Vec<u8>) {
+        let uuid = file.strip_suffix(".jpg").unwrap_or(file);
+        if !is_valid_id(uuid) {
+            return (404, "text/plain", b"Not found".to_vec());
+        }
+        if let Some(jpeg) = self.thumbnails.cache().get(uuid, size) {
+            *max_age = Some(THUMBNAIL_MAX_AGE);
+            return (200, "image/jpeg", jpeg);
+        }
+        match self.storage.get_video(uuid) {
+            Ok(Some(video)) => {
+                let path = match size {
+                    ThumbnailSize::Small => &video.thumbnail_path,
+                    ThumbnailSize::Large => &video.preview_path,
+                };
+                self.thumbnails.queue(uuid, size, &video.account.host, path);
+            }
+            Ok(None) => return (404, "text/plain", b"Not found".to_vec()),
+            Err(e) => debug!("Failed to find the video of thumbnail {} : {}", uuid, e),
+        }
+        static_file(Path::new(STATIC_DIR), PLACEHOLDER)
+    }
+
+    /// Answers the search box with a JSON array of `{ "text", "kind" }` completions
+    fn suggest(&self, params: &HashMap<String, String>) -> Result<String, Box<dyn Error>> {
+        let text = params
+            .get("query")
+            .map(|text| text.trim())
+            .unwrap_or_default();
+        if text.chars().count() < SUGGEST_MIN_LENGTH {
+            return Ok("[]".to_string());
+        }
+        let nsfw = params.get("nsfw").is_some_and(|nsfw| nsfw == "1");
+        let suggestions = self.storage.suggest(text, nsfw, SUGGESTIONS)?;
+        Ok(serde_json::to_string(&suggestions)?)
+    }
+
+    fn error_page(&self, message: &str) -> String {
+        self.templates
+            .render("error", &message)
+            .unwrap_or_else(|_| message.to_string())
+    }
+
+    fn handle(&mut self, request: Request) {
+        let (path, params) = parse_url(request.url());
+        trace!("{} {}", request.method(), request.url());
+        let mut max_age = None;
+        let (status, content_type, body) = match path.as_str() {
+            "/" => static_file(Path::new(STATIC_DIR), "index.html"),
+            "/search" => match self.search(&params) {
+                Ok(page) => (200, "text/html; charset=utf-8", page.into_bytes()),
+                Err(e) => {
+                    error!("Search for {:?} failed : {}", params.get("query"), e);
+                    let page = self.error_page("the search failed");
+                    (500, "text/html; charset=utf-8", page.into_bytes())
+                }
+            },
+            "/suggest" => match self.suggest(&params) {
+                Ok(json) => (200, "application/json", json.into_bytes()),
+                Err(e) => {
+                    error!("Suggestions for {:?} failed : {}", params.get("query"), e);
+                    (500, "application/json", b"[]".to_vec())
+                }
+            },
+            _ if path.starts_with("/thumbnails/") => self.thumbnail_reply(
+                &path["/thumbnails/".len()..],
+                ThumbnailSize::Small,
+                &mut max_age,
+            ),
+            _ if path.starts_with("/previews/") => self.thumbnail_reply(
+                &path["/previews/".len()..],
+                ThumbnailSize::Large,
+                &mut max_age,
+            ),
+            _ if path.starts_with("/video/") => {
+                match self.video_page(&path["/video/".len()..], &params) {
+                    Ok(Some(page)) => (200, "text/html; charset=utf-8", page.into_bytes()),
+                    Ok(None) => (
+                        404,
+                        "text/html; charset=utf-8",
+                        self.error_page("video not found").into_bytes(),
+                    ),
+                    Err(e) => {
+                        error!("Video page of {} failed : {}", path, e);
+                        let page = self.error_page("the video could not be shown");
+                        (500, "text/html; charset=utf-8", page.into_bytes())
+                    }
+                }
+            }
+            _ => match path.strip_prefix("/static/") {
+                Some(file) => static_file(Path::new(STATIC_DIR), file),
+                None => (
+                    404,
+                    "text/html; charset=utf-8",
+                    self.error_page("page not found").into_bytes(),
+                ),
+            },
+        };
+        let mut response = Response::from_data(body)
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+        if let Some(max_age) = max_age {
+            let cache_control = format!("public, max-age={}", max_age);
+            response.add_header(Header::from_bytes("Cache-Control", cache_control).unwrap());
+        }
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to answer a request : {}", e);
+        }
+    }
+}
+
+/// Reads a file of the static directory, refusing paths escaping it
+fn static_file(dir: &Path, file: &str) -> (u16, &'static str, Vec<u8>) {
+    let relative = Path::new(file);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return (404, "text/plain", b"Not found".to_vec());
+    }
+    match fs::read(dir.join(relative)) {
+        Ok(content) => (200, content_type(file), content),
+        Err(_) => (404, "text/plain", b"Not found".to_vec()),
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+struct Opt {
+    /// Verbose mode (-v, -vv, -vvv, etc)
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: usize,
+
+    /// Address to listen on
+    #[structopt(long = "addr", default_value = "127.0.0.1:8000")]
+    addr: String,
+
+    /// Where the videos are stored : elastic or sqlite
+    #[structopt(long = "storage", default_value = "elastic")]
+    storage: StorageKind,
+
+    /// JSON file of the ranking weights, reloaded when modified. Default weights are used when
+    /// it is missing.
+    #[structopt(long = "ranking", default_value = "ranking.json", parse(from_os_str))]
+    ranking: PathBuf,
+
+    /// Rank the results by text relevance only
+    #[structopt(long = "no-ranking")]
+    no_ranking: bool,
+
+    /// Directory where the resized thumbnails are cached
+    #[structopt(
+        long = "thumbnail-cache",
+        default_value = "thumbnail_cache",
+        parse(from_os_str)
+    )]
+    thumbnail_cache: PathBuf,
+
+    /// Size of the thumbnail cache, in MB. The least recently used thumbnails are removed
+    /// beyond it.
+    #[structopt(long = "thumbnail-cache-size", default_value = "1024")]
+    thumbnail_cache_size: u64,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    stderrlog::new()
+        .modules(vec![module_path!(), "peertube_lib"])
+        .verbosity(opt.verbose + 1)
+        .init()?;
+    let mut server = SearchServer {
+        storage: open_storage(opt.storage, &EsConfig::from_env())?,
+        thumbnails: ThumbnailFetcher::new(
+            ThumbnailCache::open(
+                opt.thumbnail_cache.clone(),
+                opt.thumbnail_cache_size * 1024 * 1024,
+            )?,
+            THUMBNAIL_THREADS,
+        ),
+        templates: load_templates(Path::new(TEMPLATES_DIR))?,
+        weights: if opt.no_ranking {
+            None
+        } else {
+            Some(WeightsFile::new(opt.ranking.clone()))
+        },
+        reputations: vec![],
+        reputations_loaded: None,
+    };
+    let http = Server::http(&opt.addr).map_err(|e| format!("{} : {}", opt.addr, e))?;
+    info!("Listening on http://{}", opt.addr);
+    for request in http.incoming_requests() {
+        server.handle(request);
+    }
+    Ok(())
+}
@@ -1,46 +1,244 @@
+use chrono::Utc;
+use peertube_lib::consistency::compare;
+use peertube_lib::crawl_output::{crawl_files, latest_snapshot, open_crawl_file};
+use peertube_lib::crawl_report::CrawlReport;
+use peertube_lib::elastic::EsConfig;
+use peertube_lib::mirrors::find_mirrors;
 use peertube_lib::peertube_api::Video;
-use std::collections::HashMap;
+use peertube_lib::validation::{validate_file, ValidationSummary};
+use peertube_lib::video_storage::{open_storage, StorageKind};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
 const DIR: &str = "./crawled";
 
-fn main() -> Result<(), Box<dyn Error>> {
+/** Number of videos sent to the storage at once when repairing it */
+const BATCH_SIZE: usize = 500;
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+struct Opt {
+    /// Snapshot directory to inspect
+    /// Uses the most recent snapshot if missing
+    #[structopt(short = "s", long = "snapshot", parse(from_os_str))]
+    snapshot: Option<PathBuf>,
+
+    /// Snapshot directory to inspect, as accepted before --snapshot existed
+    #[structopt(name = "SNAPSHOT", parse(from_os_str), conflicts_with = "snapshot")]
+    snapshot_dir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Lists the videos crawled several times under the same uuid (default)
+    #[structopt(name = "duplicates")]
+    Duplicates,
+
+    /// Clusters the videos re-uploaded on several instances, and writes a JSON and HTML report
+    #[structopt(name = "mirrors")]
+    Mirrors {
+        /// Directory of the report
+        #[structopt(
+            short = "o",
+            long = "output",
+            default_value = "report",
+            parse(from_os_str)
+        )]
+        output: PathBuf,
+    },
+
+    /// Checks every video without stopping at the first error, and prints a JSON summary.
+    /// Exits with a non-zero status if any problem is found.
+    #[structopt(name = "validate")]
+    Validate {
+        /// Writes the summary to a file instead of the standard output
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Compares the crawled videos with the stored ones, listing the missing, extra and stale
+    /// videos of each host
+    #[structopt(name = "check-index")]
+    CheckIndex {
+        /// Storage to compare with : elastic or sqlite
+        #[structopt(long = "storage", default_value = "elastic")]
+        storage: StorageKind,
+
+        /// Sends the missing and stale videos to the storage
+        #[structopt(long = "repair")]
+        repair: bool,
+
+        /// Writes the differences as JSON to a file
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+fn read_videos(snapshot: &Path) -> Result<Vec<Video>, Box<dyn Error>> {
+    let mut videos = vec![];
+    for file in crawl_files(snapshot)? {
+        for line in open_crawl_file(&file)?.lines() {
+            match serde_json::from_str(&line?) {
+                Ok(video) => videos.push(video),
+                Err(e) => eprintln!("Skipping malformed video in {:?} : {}", file, e),
+            }
+        }
+    }
+    Ok(videos)
+}
+
+fn duplicates(snapshot: &Path) -> Result<(), Box<dyn Error>> {
     let mut data: HashMap<String, Video> = HashMap::new();
-    println!("Starting inspection");
+    println!("Starting inspection of {}", snapshot.to_str().unwrap());
     let mut total = 0;
-    let files = fs::read_dir(DIR)?;
-    for file_res in files {
-        if let Ok(file) = file_res {
-            println!("Opening {}", file.path().to_str().unwrap());
-            let input = File::open(file.path())?;
-            let buffer = BufReader::new(input);
-            let mut count = 0;
-            for line in buffer.lines() {
-                count += 1;
-                let video: Video = serde_json::from_str(&line?)?;
-                if data.contains_key(&video.uuid) {
-                    let v = data.get(&video.uuid).unwrap();
-                    println!(
-                        "Found duplicate : {} ({})from {}@{} already exists ({} ({})from {}@{})",
-                        video.name,
-                        video.uuid,
-                        video.account.display_name,
-                        video.account.host,
-                        v.name,
-                        v.uuid,
-                        v.account.display_name,
-                        v.account.host
-                    );
-                } else {
-                    data.insert(video.uuid.clone(), video);
-                }
+    for file in crawl_files(snapshot)? {
+        println!("Opening {}", file.to_str().unwrap());
+        let buffer = open_crawl_file(&file)?;
+        let mut count = 0;
+        for line in buffer.lines() {
+            count += 1;
+            let video: Video = serde_json::from_str(&line?)?;
+            if data.contains_key(&video.uuid) {
+                let v = data.get(&video.uuid).unwrap();
+                println!(
+                    "Found duplicate : {} ({})from {}@{} already exists ({} ({})from {}@{})",
+                    video.name,
+                    video.uuid,
+                    video.account.display_name,
+                    video.account.host,
+                    v.name,
+                    v.uuid,
+                    v.account.display_name,
+                    v.account.host
+                );
+            } else {
+                data.insert(video.uuid.clone(), video);
             }
-            total += count;
-            println!("Inspected {} videos", count)
         }
+        total += count;
+        println!("Inspected {} videos", count)
     }
     println!("There are {} videos in the BDD", total);
     Ok(())
 }
+
+fn mirrors(snapshot: &Path, output: &Path) -> Result<(), Box<dyn Error>> {
+    let videos = read_videos(snapshot)?;
+    let report = find_mirrors(&videos);
+    fs::create_dir_all(output)?;
+    fs::write(
+        output.join("mirrors.json"),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    fs::write(output.join("mirrors.html"), report.to_html()?)?;
+    println!(
+        "Found {} clusters of mirrors among {} videos, report written to {}",
+        report.clusters.len(),
+        report.videos,
+        output.to_str().unwrap()
+    );
+    Ok(())
+}
+
+fn validate(snapshot: &Path, output: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let now = Utc::now();
+    let mut reports = vec![];
+    for file in crawl_files(snapshot)? {
+        let report = validate_file(&file, now)?;
+        eprintln!(
+            "{} : {}/{} valid lines",
+            report.file, report.valid, report.lines
+        );
+        reports.push(report);
+    }
+    let summary = ValidationSummary::new(reports);
+    let json = serde_json::to_string_pretty(&summary)?;
+    match output {
+        Some(path) => fs::write(path, json)?,
+        None => println!("{}", json),
+    }
+    if !summary.valid {
+        eprintln!(
+            "{} of {} files contain invalid videos",
+            summary.invalid_files, summary.files
+        );
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn check_index(
+    snapshot: &Path,
+    kind: StorageKind,
+    repair: bool,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let videos = read_videos(snapshot)?;
+    let complete_hosts: HashSet<String> = match CrawlReport::read(snapshot)? {
+        Some(report) => report
+            .instances
+            .into_iter()
+            .filter(|(_, stats)| stats.complete)
+            .map(|(host, _)| host)
+            .collect(),
+        None => {
+            eprintln!("No crawl report in the snapshot, extra videos are not listed");
+            HashSet::new()
+        }
+    };
+    let mut storage = open_storage(kind, &EsConfig::from_env())?;
+    let differences = compare(&videos, &storage.list_videos()?, &complete_hosts);
+    for host in &differences {
+        println!(
+            "{} : {} missing, {} extra, {} stale",
+            host.host,
+            host.missing.len(),
+            host.extra.len(),
+            host.stale.len()
+        );
+    }
+    println!("{} hosts differ from the crawl", differences.len());
+    if let Some(path) = output {
+        fs::write(path, serde_json::to_string_pretty(&differences)?)?;
+    }
+    if repair {
+        let outdated: HashSet<&str> = differences
+            .iter()
+            .flat_map(|host| host.missing.iter().chain(host.stale.iter()))
+            .map(String::as_str)
+            .collect();
+        let to_send: Vec<Video> = videos
+            .into_iter()
+            .filter(|video| outdated.contains(video.uuid.as_str()))
+            .collect();
+        for batch in to_send.chunks(BATCH_SIZE) {
+            storage.store_videos(batch)?;
+        }
+        println!("Sent {} videos to the {:?} storage", to_send.len(), kind);
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    let snapshot = match opt.snapshot.or(opt.snapshot_dir) {
+        Some(dir) => dir,
+        None => latest_snapshot(Path::new(DIR))?,
+    };
+    match opt.command.unwrap_or(Command::Duplicates) {
+        Command::Duplicates => duplicates(&snapshot),
+        Command::Mirrors { output } => mirrors(&snapshot, &output),
+        Command::Validate { output } => validate(&snapshot, output),
+        Command::CheckIndex {
+            storage,
+            repair,
+            output,
+        } => check_index(&snapshot, storage, repair, output),
+    }
+}
@@ -0,0 +1,131 @@
+/// This module compares a crawl with the videos actually stored in the index
+use crate::peertube_api::Video;
+use crate::video_storage::StoredVideo;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Differences between the crawl and the index for one host
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct HostConsistency {
+    pub host: String,
+    /// Crawled but not indexed
+    pub missing: Vec<String>,
+    /// Indexed but not crawled, only listed for the hosts crawled completely
+    pub extra: Vec<String>,
+    /// Indexed with a different `updatedAt` than the crawled video
+    pub stale: Vec<String>,
+}
+
+impl HostConsistency {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.stale.is_empty()
+    }
+}
+
+fn entry<'a>(
+    hosts: &'a mut BTreeMap<String, HostConsistency>,
+    host: &str,
+) -> &'a mut HostConsistency {
+    hosts
+        .entry(host.to_string())
+        .or_insert_with(|| HostConsistency {
+            host: host.to_string(),
+            ..HostConsistency::default()
+        })
+}
+
+/// Compares the crawled and stored videos, returning the hosts that differ. Stored videos missing
+/// from the crawl are only extra on the `complete_hosts`, whose every video was crawled : the
+/// videos of the other hosts may just be beyond the part that was crawled.
+pub fn compare(
+    crawled: &[Video],
+    stored: &[StoredVideo],
+    complete_hosts: &HashSet<String>,
+) -> Vec<HostConsistency> {
+    let stored_by_uuid: HashMap<&str, &StoredVideo> =
+        stored.iter().map(|v| (v.uuid.as_str(), v)).collect();
+    let crawled_by_uuid: HashMap<&str, &Video> =
+        crawled.iter().map(|v| (v.uuid.as_str(), v)).collect();
+    let mut hosts: BTreeMap<String, HostConsistency> = BTreeMap::new();
+    for (uuid, video) in &crawled_by_uuid {
+        match stored_by_uuid.get(uuid) {
+            None => entry(&mut hosts, &video.account.host)
+                .missing
+                .push(uuid.to_string()),
+            Some(stored) if stored.updated_at != video.updated_at => {
+                entry(&mut hosts, &video.account.host)
+                    .stale
+                    .push(uuid.to_string())
+            }
+            Some(_) => (),
+        }
+    }
+    for (uuid, stored) in &stored_by_uuid {
+        if !crawled_by_uuid.contains_key(uuid) && complete_hosts.contains(&stored.host) {
+            entry(&mut hosts, &stored.host).extra.push(uuid.to_string());
+        }
+    }
+    hosts
+        .into_values()
+        .map(|mut host| {
+            host.missing.sort();
+            host.extra.sort();
+            host.stale.sort();
+            host
+        })
+        .filter(|host| !host.is_consistent())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::consistency::compare;
+    use crate::peertube_api::Video;
+    use crate::video_storage::StoredVideo;
+    use std::collections::HashSet;
+
+    fn stored(video: &Video) -> StoredVideo {
+        StoredVideo {
+            uuid: video.uuid.clone(),
+            host: video.account.host.clone(),
+            updated_at: video.updated_at.clone(),
+        }
+    }
+
+    #[test]
+    fn consistency() {
+        let json = include_str!("../tests/video1.json");
+        let video: Video = serde_json::from_str(json).unwrap();
+        let host = video.account.host.clone();
+        let mut missing = video.clone();
+        missing.uuid = "missing".to_string();
+        let mut stale = video.clone();
+        stale.uuid = "stale".to_string();
+        let mut extra = video.clone();
+        extra.uuid = "extra".to_string();
+        let mut elsewhere = extra.clone();
+        elsewhere.uuid = "elsewhere".to_string();
+        elsewhere.account.host = "partial.example".to_string();
+
+        let crawled = [video.clone(), missing, stale.clone()];
+        let mut stored_stale = stored(&stale);
+        stored_stale.updated_at = "2000-01-01T00:00:00.000Z".to_string();
+        let index = [
+            stored(&video),
+            stored_stale,
+            stored(&extra),
+            stored(&elsewhere),
+        ];
+        let complete: HashSet<String> = [host.clone()].iter().cloned().collect();
+        let differences = compare(&crawled, &index, &complete);
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].host, host);
+        assert_eq!(differences[0].missing, vec!["missing"]);
+        assert_eq!(differences[0].stale, vec!["stale"]);
+        assert_eq!(differences[0].extra, vec!["extra"]);
+
+        assert!(compare(&crawled, &index, &HashSet::new())[0]
+            .extra
+            .is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+/// This module bounds the size of a crawl, to run cheap but representative crawls
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CrawlLimits {
+    /// Videos fetched from each instance
+    pub max_videos: Option<u64>,
+    /// Pages of videos fetched from each instance
+    pub max_pages: Option<u64>,
+    /// Instances crawled in total
+    pub max_instances: Option<u64>,
+    /// Hops from the seed instances, which are at depth 0
+    pub max_depth: Option<u32>,
+}
+
+impl CrawlLimits {
+    pub fn allows_instance(&self, crawled: u64) -> bool {
+        self.max_instances.is_none_or(|max| crawled < max)
+    }
+
+    pub fn allows_depth(&self, depth: u32) -> bool {
+        self.max_depth.is_none_or(|max| depth <= max)
+    }
+
+    pub fn allows_page(&self, fetched_pages: u64) -> bool {
+        self.max_pages.is_none_or(|max| fetched_pages < max)
+    }
+
+    /// Number of videos to fetch from an instance publishing `total` videos
+    pub fn videos_to_fetch(&self, total: u64) -> u64 {
+        self.max_videos.map_or(total, |max| total.min(max))
+    }
+}
+
+/// Parses a sampling rate, which must be between 0 and 1
+pub fn parse_sample_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        Ok(_) => Err(format!("{} is not between 0 and 1", value)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Picks a random subset of the instances, reproducible when seeded
+pub struct Sampler {
+    rate: f64,
+    rng: StdRng,
+}
+
+impl Sampler {
+    /// Keeps each instance with a probability of `rate`
+    pub fn new(rate: f64, seed: Option<u64>) -> Sampler {
+        Sampler {
+            rate: rate.clamp(0.0, 1.0),
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+        }
+    }
+
+    pub fn keep(&mut self) -> bool {
+        self.rng.gen_bool(self.rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::crawl_limits::{parse_sample_rate, CrawlLimits, Sampler};
+
+    #[test]
+    fn crawl_limits() {
+        let limits = CrawlLimits {
+            max_videos: Some(250),
+            max_pages: Some(2),
+            max_instances: Some(10),
+            max_depth: Some(1),
+        };
+        assert_eq!(limits.videos_to_fetch(1000), 250);
+        assert_eq!(limits.videos_to_fetch(20), 20);
+        assert!(limits.allows_page(1) && !limits.allows_page(2));
+        assert!(limits.allows_instance(9) && !limits.allows_instance(10));
+        assert!(limits.allows_depth(1) && !limits.allows_depth(2));
+        assert!(CrawlLimits::default().allows_instance(u64::MAX));
+
+        let kept = |seed| {
+            let mut sampler = Sampler::new(0.5, Some(seed));
+            (0..100).filter(|_| sampler.keep()).count()
+        };
+        assert_eq!(kept(1), kept(1));
+        assert!(kept(1) > 20 && kept(1) < 80);
+        assert_eq!(parse_sample_rate("0.1"), Ok(0.1));
+        assert!(parse_sample_rate("NaN").is_err());
+        assert!(parse_sample_rate("inf").is_err());
+        assert!(parse_sample_rate("1.5").is_err());
+    }
+}
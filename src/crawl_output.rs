@@ -0,0 +1,381 @@
+/// This module writes and reads the crawled videos, stored as JSON lines in one snapshot
+/// directory per crawl run
+use crate::peertube_api::Video;
+use chrono::{NaiveDateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const SNAPSHOT_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// Where the crawl of an instance stopped when it failed midway
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResumePoint {
+    /// Start of the next page of the video list
+    pub offset: u64,
+    /// Videos written to the crawl file, fewer than `offset` when some were left out by the
+    /// filters or the video policy
+    pub lines: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "json",
+            Compression::Gzip => "json.gz",
+            Compression::Zstd => "json.zst",
+        }
+    }
+
+    /// Guesses the compression of a crawl file from its name
+    pub fn from_path(path: &Path) -> Option<Compression> {
+        let name = path.file_name()?.to_str()?;
+        [Compression::Gzip, Compression::Zstd, Compression::None]
+            .iter()
+            .find(|c| name.ends_with(&format!(".{}", c.extension())))
+            .cloned()
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "Unknown compression {} (expected none, gzip or zstd)",
+                s
+            )),
+        }
+    }
+}
+
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+/// Streams videos to a crawl file, one JSON document per line
+pub struct VideoWriter {
+    path: PathBuf,
+    encoder: Encoder,
+}
+
+impl VideoWriter {
+    /// Creates `<dir>/<host>.<extension>`. The previous files of the host, whatever their
+    /// compression, are unlinked rather than truncated, as they may be shared with an older
+    /// snapshot.
+    pub fn create(dir: &Path, host: &str, compression: Compression) -> io::Result<VideoWriter> {
+        for previous in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let path = dir.join(format!("{}.{}", host, previous.extension()));
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        let path = dir.join(format!("{}.{}", host, compression.extension()));
+        let file = BufWriter::new(File::create(&path)?);
+        let encoder = match compression {
+            Compression::None => Encoder::Plain(file),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        };
+        Ok(VideoWriter { path, encoder })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_video(&mut self, video: &Video) -> io::Result<()> {
+        self.write_line(&serde_json::to_vec(video)?)
+    }
+
+    /// Writes an already serialized video
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = [line, b"\n"].concat();
+        match &mut self.encoder {
+            Encoder::Plain(w) => w.write_all(&line),
+            Encoder::Gzip(w) => w.write_all(&line),
+            Encoder::Zstd(w) => w.write_all(&line),
+        }
+    }
+
+    /// Flushes the file and writes the compression trailer
+    pub fn finish(self) -> io::Result<()> {
+        match self.encoder {
+            Encoder::Plain(mut w) => w.flush(),
+            Encoder::Gzip(w) => w.finish()?.flush(),
+            Encoder::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+/// Opens a crawl file, decompressing it according to its extension
+pub fn open_crawl_file(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    Ok(match Compression::from_path(path) {
+        Some(Compression::Gzip) => Box::new(BufReader::new(GzDecoder::new(file))),
+        Some(Compression::Zstd) => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+        _ => Box::new(BufReader::new(file)),
+    })
+}
+
+/// Lists the crawl files of a directory
+pub fn crawl_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && Compression::from_path(path).is_some())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Links the crawl files of a snapshot into another one, copying them where hard links are not
+/// supported, and returns their number
+pub fn link_crawl_files(from: &Path, to: &Path) -> io::Result<usize> {
+    let files = crawl_files(from)?;
+    for file in &files {
+        let target = to.join(file.file_name().unwrap_or_default());
+        if fs::hard_link(file, &target).is_err() {
+            fs::copy(file, &target)?;
+        }
+    }
+    Ok(files.len())
+}
+
+/// Returns the crawl file of a host in a snapshot, whatever its compression
+pub fn find_crawl_file(dir: &Path, host: &str) -> Option<PathBuf> {
+    [Compression::None, Compression::Gzip, Compression::Zstd]
+        .iter()
+        .map(|compression| dir.join(format!("{}.{}", host, compression.extension())))
+        .find(|path| path.is_file())
+}
+
+/// Starts the crawl file of a host in the snapshot `dir` with the videos written before `point`
+/// in its most recent crawl file under `root`, so that a crawl resumed at `point.offset` yields
+/// every video. Returns None when no crawl file holds that many videos.
+pub fn resume_crawl_file(
+    root: &Path,
+    dir: &Path,
+    host: &str,
+    compression: Compression,
+    point: ResumePoint,
+) -> io::Result<Option<VideoWriter>> {
+    let count = point.lines;
+    if count == 0 {
+        return VideoWriter::create(dir, host, compression).map(Some);
+    }
+    let mut snapshots = list_snapshots(root)?;
+    if !snapshots.iter().any(|snapshot| snapshot == dir) {
+        snapshots.push(dir.to_path_buf());
+    }
+    for snapshot in snapshots.iter().rev() {
+        let file = match find_crawl_file(snapshot, host) {
+            Some(file) => file,
+            None => continue,
+        };
+        // Read before creating the new file, which may replace this one
+        let lines: Vec<Vec<u8>> = open_crawl_file(&file)?
+            .split(b'\n')
+            .take(count as usize)
+            .map_while(Result::ok)
+            .collect();
+        if (lines.len() as u64) < count {
+            continue;
+        }
+        let mut writer = VideoWriter::create(dir, host, compression)?;
+        for line in lines {
+            writer.write_line(&line)?;
+        }
+        return Ok(Some(writer));
+    }
+    Ok(None)
+}
+
+/// Returns the host a crawl file was fetched from
+pub fn crawl_file_host(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let extension = Compression::from_path(path)?.extension();
+    Some(name[..name.len() - extension.len() - 1].to_string())
+}
+
+/// Creates a new snapshot directory named after the current UTC time, followed by `.<n>` when
+/// another run created a snapshot within the same second
+pub fn create_snapshot(root: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(root)?;
+    let name = Utc::now().format(SNAPSHOT_FORMAT).to_string();
+    let mut suffix = 0;
+    loop {
+        let dir = match suffix {
+            0 => root.join(&name),
+            _ => root.join(format!("{}.{}", name, suffix)),
+        };
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Splits a snapshot name into its date and collision suffix
+fn parse_snapshot_name(path: &Path) -> Option<(NaiveDateTime, u32)> {
+    let name = path.file_name()?.to_str()?;
+    let (date, suffix) = match name.split_once('.') {
+        Some((date, suffix)) => (date, suffix.parse().ok()?),
+        None => (name, 0),
+    };
+    let date = NaiveDateTime::parse_from_str(date, SNAPSHOT_FORMAT).ok()?;
+    Some((date, suffix))
+}
+
+/// Lists the snapshot directories, oldest first
+pub fn list_snapshots(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots: Vec<PathBuf> = fs::read_dir(root)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && snapshot_date(path).is_some())
+        .collect();
+    snapshots.sort_by_key(|path| parse_snapshot_name(path));
+    Ok(snapshots)
+}
+
+/// The date of the crawl run that produced a snapshot, in UTC
+pub fn snapshot_date(path: &Path) -> Option<NaiveDateTime> {
+    parse_snapshot_name(path).map(|(date, _)| date)
+}
+
+/// Returns the most recent snapshot, or the root itself for crawls made before snapshots existed
+pub fn latest_snapshot(root: &Path) -> io::Result<PathBuf> {
+    Ok(list_snapshots(root)?
+        .pop()
+        .unwrap_or_else(|| root.to_path_buf()))
+}
+
+/// Deletes the oldest snapshots so that at most `keep` remain, returning the deleted ones. The
+/// latest snapshot is always kept.
+pub fn rotate_snapshots(root: &Path, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let snapshots = list_snapshots(root)?;
+    let count = snapshots.len().saturating_sub(keep.max(1));
+    let removed: Vec<PathBuf> = snapshots.into_iter().take(count).collect();
+    for dir in &removed {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::crawl_output::{
+        crawl_file_host, create_snapshot, find_crawl_file, list_snapshots, open_crawl_file,
+        resume_crawl_file, rotate_snapshots, snapshot_date, Compression, ResumePoint, VideoWriter,
+    };
+    use crate::peertube_api::Video;
+    use crate::video_policy::{NsfwPolicy, PolicyBucket, VideoPolicy};
+    use std::io::BufRead;
+
+    #[test]
+    fn crawl_output() {
+        let json = include_str!("../tests/video1.json");
+        let video: Video = serde_json::from_str(json).unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("peertube_se_crawl_output_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let mut writer = VideoWriter::create(&dir, "peertube.example", *compression).unwrap();
+            writer.write_video(&video).unwrap();
+            writer.write_video(&video).unwrap();
+            let path = writer.path().to_path_buf();
+            writer.finish().unwrap();
+            assert_eq!(Compression::from_path(&path), Some(*compression));
+            assert_eq!(crawl_file_host(&path).unwrap(), "peertube.example");
+            let lines: Vec<String> = open_crawl_file(&path)
+                .unwrap()
+                .lines()
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(lines.len(), 2);
+            let read: Video = serde_json::from_str(&lines[1]).unwrap();
+            assert_eq!(read.uuid, video.uuid);
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots() {
+        let root =
+            std::env::temp_dir().join(format!("peertube_se_snapshots_{}", std::process::id()));
+        let first = create_snapshot(&root).unwrap();
+        let second = create_snapshot(&root).unwrap();
+        assert_ne!(first, second);
+        assert!(snapshot_date(&second).is_some());
+        assert_eq!(list_snapshots(&root).unwrap().last(), Some(&second));
+
+        // A page of three videos, one of them left out by the video policy
+        let video: Video = serde_json::from_str(include_str!("../tests/video1.json")).unwrap();
+        let mut nsfw = video.clone();
+        nsfw.nsfw = true;
+        let policy = VideoPolicy::new(NsfwPolicy::Skip);
+        let host = "peertube.example";
+        let mut writer = VideoWriter::create(&first, host, Compression::None).unwrap();
+        let mut point = ResumePoint::default();
+        for page_video in &[video.clone(), nsfw, video.clone()] {
+            let mut page_video = page_video.clone();
+            point.offset += 1;
+            if policy.apply(&mut page_video) == PolicyBucket::Indexed {
+                writer.write_video(&page_video).unwrap();
+                point.lines += 1;
+            }
+        }
+        writer.finish().unwrap();
+        assert_eq!(
+            point,
+            ResumePoint {
+                offset: 3,
+                lines: 2
+            }
+        );
+        // Counting the skipped video as written leaves the crawl file too short to resume
+        let counted = ResumePoint {
+            lines: point.offset,
+            ..point
+        };
+        assert!(
+            resume_crawl_file(&root, &second, host, Compression::Gzip, counted)
+                .unwrap()
+                .is_none()
+        );
+        let mut writer = resume_crawl_file(&root, &second, host, Compression::Gzip, point)
+            .unwrap()
+            .unwrap();
+        writer.write_video(&video).unwrap();
+        writer.finish().unwrap();
+        let path = find_crawl_file(&second, host).unwrap();
+        assert_eq!(open_crawl_file(&path).unwrap().lines().count(), 3);
+
+        assert_eq!(rotate_snapshots(&root, 0).unwrap().len(), 1);
+        assert_eq!(list_snapshots(&root).unwrap(), vec![second]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+/// This module gathers statistics during a crawl, and writes them as a JSON and HTML report
+use crate::html::render_report;
+use crate::peertube_api::Video;
+use handlebars::RenderError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/** Number of hosts listed in the slowest hosts ranking */
+const SLOWEST_HOSTS: usize = 20;
+
+/** Number of previous runs shown in the HTML report */
+const HISTORY_LENGTH: usize = 10;
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// Classifies an HTTP client error, so that errors can be counted by kind
+pub fn http_error_kind(e: &isahc::Error) -> &'static str {
+    match e {
+        isahc::Error::ConnectFailed => "connect_failed",
+        isahc::Error::CouldntResolveHost => "dns",
+        isahc::Error::BadServerCertificate(_)
+        | isahc::Error::SSLConnectFailed(_)
+        | isahc::Error::SSLEngineError(_) => "tls",
+        isahc::Error::Timeout => "timeout",
+        isahc::Error::TooManyRedirects => "too_many_redirects",
+        isahc::Error::NoResponse | isahc::Error::ResponseBodyError(_) => "no_response",
+        _ => "other",
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InstanceStats {
+    pub reachable: bool,
+    pub version: Option<String>,
+    pub videos: u64,
+    pub views: i64,
+    pub duration: i64,
+    pub elapsed_ms: u64,
+    pub errors: u64,
+    /// Publication date of the most recent video
+    pub latest_published_at: Option<String>,
+    /// Whether every video of the instance was fetched, without errors nor limits
+    #[serde(default)]
+    pub complete: bool,
+}
+
+/// Totals of a crawl run, kept in the history to compare runs
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunSummary {
+    pub date: String,
+    pub elapsed_secs: u64,
+    pub instances_discovered: u64,
+    pub instances_reachable: u64,
+    pub videos: u64,
+    pub views: i64,
+    pub duration: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CrawlReport {
+    pub summary: RunSummary,
+    pub versions: BTreeMap<String, u64>,
+    pub languages: BTreeMap<String, u64>,
+    pub categories: BTreeMap<String, u64>,
+    pub errors: BTreeMap<String, u64>,
+    /// Hosts and the time spent crawling them, in milliseconds
+    pub slowest_hosts: Vec<(String, u64)>,
+    pub instances: BTreeMap<String, InstanceStats>,
+}
+
+impl CrawlReport {
+    pub fn new(date: String) -> CrawlReport {
+        CrawlReport {
+            summary: RunSummary {
+                date,
+                ..RunSummary::default()
+            },
+            ..CrawlReport::default()
+        }
+    }
+
+    pub fn instance(&mut self, host: &str) -> &mut InstanceStats {
+        self.instances.entry(host.to_string()).or_default()
+    }
+
+    pub fn add_videos(&mut self, host: &str, videos: &[Video]) {
+        for video in videos {
+            *self
+                .languages
+                .entry(video.language.label.clone())
+                .or_default() += 1;
+            *self
+                .categories
+                .entry(video.category.label.clone())
+                .or_default() += 1;
+            let instance = self.instance(host);
+            instance.videos += 1;
+            instance.views += video.views;
+            instance.duration += video.duration;
+            if instance.latest_published_at.as_ref() < Some(&video.published_at) {
+                instance.latest_published_at = Some(video.published_at.clone());
+            }
+        }
+    }
+
+    pub fn add_error(&mut self, host: &str, kind: &str) {
+        *self.errors.entry(kind.to_string()).or_default() += 1;
+        self.instance(host).errors += 1;
+    }
+
+    /// Reads the report of a snapshot, None for snapshots written before reports existed
+    pub fn read(snapshot: &Path) -> io::Result<Option<CrawlReport>> {
+        match fs::read_to_string(snapshot.join("report.json")) {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Computes the totals and rankings once the crawl is over
+    pub fn finish(&mut self, instances_discovered: u64, elapsed_secs: u64) {
+        let instances = self.instances.values();
+        self.summary.elapsed_secs = elapsed_secs;
+        self.summary.instances_discovered = instances_discovered;
+        self.summary.instances_reachable = instances.clone().filter(|i| i.reachable).count() as u64;
+        self.summary.videos = instances.clone().map(|i| i.videos).sum();
+        self.summary.views = instances.clone().map(|i| i.views).sum();
+        self.summary.duration = instances.map(|i| i.duration).sum();
+        self.versions.clear();
+        for version in self.instances.values().filter_map(|i| i.version.clone()) {
+            *self.versions.entry(version).or_default() += 1;
+        }
+        let mut slowest: Vec<(String, u64)> = self
+            .instances
+            .iter()
+            .map(|(host, i)| (host.clone(), i.elapsed_ms))
+            .collect();
+        slowest.sort_by_key(|(_, elapsed)| std::cmp::Reverse(*elapsed));
+        slowest.truncate(SLOWEST_HOSTS);
+        self.slowest_hosts = slowest;
+    }
+
+    /// Writes `report.json` and `report.html` in the snapshot directory, and appends the run to
+    /// the history kept in the output directory
+    pub fn write(&self, snapshot: &Path, root: &Path) -> io::Result<()> {
+        let history = read_history(root)?;
+        fs::write(
+            snapshot.join("report.json"),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        let html = self.to_html(&history).map_err(io::Error::other)?;
+        fs::write(snapshot.join("report.html"), html)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(root.join(HISTORY_FILE))?;
+        writeln!(file, "{}", serde_json::to_string(&self.summary)?)
+    }
+
+    pub fn to_html(&self, history: &[RunSummary]) -> Result<String, RenderError> {
+        let start = history.len().saturating_sub(HISTORY_LENGTH);
+        let runs: Vec<RunView> = history[start..]
+            .iter()
+            .chain(std::iter::once(&self.summary))
+            .map(|run| RunView {
+                run,
+                hours: run.duration / 3600,
+            })
+            .collect();
+        let tables: Vec<CountTable> = [
+            ("PeerTube versions", &self.versions),
+            ("Languages", &self.languages),
+            ("Categories", &self.categories),
+            ("Errors", &self.errors),
+        ]
+        .iter()
+        .map(|(title, counts)| {
+            let mut rows: Vec<(&String, &u64)> = counts.iter().collect();
+            rows.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+            CountTable { title, rows }
+        })
+        .collect();
+        let slowest_hosts: Vec<(&String, String)> = self
+            .slowest_hosts
+            .iter()
+            .map(|(host, elapsed)| (host, format!("{:.1}", *elapsed as f64 / 1000.0)))
+            .collect();
+        render_report(
+            include_str!("../templates/reports/crawl.html.hbs"),
+            &json!({
+                "title": format!("Crawl of {}", self.summary.date),
+                "runs": runs,
+                "tables": tables,
+                "slowest_hosts": slowest_hosts,
+            }),
+        )
+    }
+}
+
+/// A run as shown in the HTML report
+#[derive(Serialize)]
+struct RunView<'a> {
+    #[serde(flatten)]
+    run: &'a RunSummary,
+    hours: i64,
+}
+
+/// Counts by key, most frequent first
+#[derive(Serialize)]
+struct CountTable<'a> {
+    title: &'a str,
+    rows: Vec<(&'a String, &'a u64)>,
+}
+
+/// Reads the summaries of the previous runs, oldest first
+pub fn read_history(root: &Path) -> io::Result<Vec<RunSummary>> {
+    let file = match fs::File::open(root.join(HISTORY_FILE)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::crawl_report::{CrawlReport, RunSummary};
+    use crate::peertube_api::Video;
+
+    #[test]
+    fn crawl_report() {
+        let json = include_str!("../tests/video1.json");
+        let video: Video = serde_json::from_str(json).unwrap();
+        let mut report = CrawlReport::new("2020-05-01T00:00:00+00:00".to_string());
+        report.instance("fast.example").reachable = true;
+        report.instance("slow.example").elapsed_ms = 2500;
+        report.add_videos("fast.example", &[video.clone(), video]);
+        report.add_error("slow.example", "timeout");
+        report.finish(3, 60);
+        assert_eq!(report.summary.instances_reachable, 1);
+        assert_eq!(report.summary.videos, 2);
+        assert_eq!(report.slowest_hosts[0], ("slow.example".to_string(), 2500));
+        assert_eq!(report.errors["timeout"], 1);
+
+        let previous = RunSummary {
+            date: "<previous>".to_string(),
+            duration: 7200,
+            ..RunSummary::default()
+        };
+        let html = report.to_html(&[previous]).unwrap();
+        assert!(html.contains("<title>Crawl of 2020-05-01T00:00:00+00:00</title>"));
+        assert!(html.contains("&lt;previous&gt;"));
+        assert!(html.contains("<td>slow.example</td><td>2.5 s</td>"));
+        assert!(html.contains("<td>timeout</td><td>1</td>"));
+    }
+}
@@ -1,52 +1,730 @@
-use isahc::{HttpClient, ResponseExt};
+/// This module administrates the Elastic Search cluster : connection settings, health checks,
+/// and the creation, update and reindexing of the video index
+use crate::peertube_api::Video;
+use base64::Engine;
+use isahc::config::{ClientCertificate, PrivateKey};
+use isahc::http::{Method, Request, Response, StatusCode};
+use isahc::prelude::*;
+use isahc::{Body, HttpClient};
+use log::warn;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::fmt;
+use std::path::PathBuf;
 
-enum Index {
-    IndexIsPresent,
-    IndexIsMissing,
+/// Address of the Elastic Search instance
+pub const ES_ADDR: &str = "http://localhost:9200";
+
+/// Alias of the current video index. Indices are named after the version of their mappings, so
+/// that a new index can be filled while the current one is searched.
+pub const INDEX_ALIAS: &str = "peertube_se";
+
+/// Mappings of the video index. `mappings._meta.version` must be raised whenever they change, so
+/// that existing indices get the new fields.
+pub const MAPPINGS: &str = include_str!("../es_mappings.json");
+
+/// Languages whose name and description are indexed with a dedicated analyzer, under
+/// `i18n.<language>`
+pub const ANALYZED_LANGUAGES: &[&str] = &[
+    "ar", "ca", "cs", "da", "de", "el", "en", "es", "fi", "fr", "hu", "it", "nl", "no", "pt", "ro",
+    "ru", "sv", "tr",
+];
+
+/** Oldest major version of Elastic Search supporting typeless mappings */
+const MIN_ES_VERSION: u64 = 7;
+
+#[derive(Debug)]
+pub enum EsError {
+    /// The connection settings are invalid
+    Config(String),
+    /// The request could not be sent, or no answer was received
+    Http(isahc::Error),
+    /// Elastic Search answered with an error status
+    Status {
+        status: StatusCode,
+        body: String,
+    },
+    /// The answer is not the expected JSON
+    InvalidResponse(String),
+    /// Some of the videos sent to the bulk API were not indexed
+    Rejected(String),
+    /// The cluster is red, or runs an unsupported version
+    Unhealthy(String),
+    /// The index cannot be updated in place, and must be reindexed
+    MappingConflict(Vec<String>),
+    /// The reindexed index does not hold every video
+    CountMismatch {
+        source: String,
+        source_count: u64,
+        target: String,
+        target_count: u64,
+    },
+    NothingToReindex,
+    /// The index already uses the current mappings
+    AlreadyCurrent(String),
+}
+
+impl fmt::Display for EsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EsError::Config(e) => write!(f, "Invalid Elastic Search settings : {}", e),
+            EsError::Http(e) => write!(f, "Failed to reach Elastic Search : {}", e),
+            EsError::Status { status, body } => {
+                write!(f, "Elastic Search answered {} : {}", status, body)
+            }
+            EsError::InvalidResponse(e) => {
+                write!(f, "Elastic Search replied with an invalid response : {}", e)
+            }
+            EsError::Rejected(e) => write!(f, "Elastic Search failed to index videos : {}", e),
+            EsError::Unhealthy(e) => write!(f, "Elastic Search is unusable : {}", e),
+            EsError::MappingConflict(fields) => write!(
+                f,
+                "The mappings of {} changed for {}, run `indexer --reindex`",
+                INDEX_ALIAS,
+                fields.join(", ")
+            ),
+            EsError::CountMismatch {
+                source,
+                source_count,
+                target,
+                target_count,
+            } => write!(
+                f,
+                "{} holds {} videos but {} holds {}, the alias was not moved",
+                target, target_count, source, source_count
+            ),
+            EsError::NothingToReindex => write!(f, "There is no index to reindex"),
+            EsError::AlreadyCurrent(index) => write!(
+                f,
+                "{} already uses the current mappings, raise mappings._meta.version in \
+                 es_mappings.json to reindex",
+                index
+            ),
+        }
+    }
+}
+
+impl Error for EsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EsError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<isahc::Error> for EsError {
+    fn from(e: isahc::Error) -> Self {
+        EsError::Http(e)
+    }
+}
+
+impl From<isahc::http::Error> for EsError {
+    fn from(e: isahc::http::Error) -> Self {
+        EsError::Config(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for EsError {
+    fn from(e: serde_json::Error) -> Self {
+        EsError::InvalidResponse(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EsAuth {
+    Basic { user: String, password: String },
+    ApiKey(String),
+}
+
+impl EsAuth {
+    fn header(&self) -> String {
+        match self {
+            EsAuth::Basic { user, password } => format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+            ),
+            EsAuth::ApiKey(key) => format!("ApiKey {}", key),
+        }
+    }
+}
+
+/// How to reach the cluster. Certificate authorities other than the system ones are given to
+/// OpenSSL with the `SSL_CERT_FILE` variable.
+#[derive(Debug, Clone, Default)]
+pub struct EsConfig {
+    pub addr: String,
+    pub auth: Option<EsAuth>,
+    /// PEM certificate and key authenticating the client, for clusters requiring mutual TLS
+    pub client_certificate: Option<(PathBuf, PathBuf)>,
+    /// Accept any server certificate, for self-signed test clusters only
+    pub insecure: bool,
+}
+
+impl EsConfig {
+    pub fn new(addr: &str) -> EsConfig {
+        EsConfig {
+            addr: addr.trim_end_matches('/').to_string(),
+            ..EsConfig::default()
+        }
+    }
+
+    /// Reads the settings from `ES_ADDR`, `ES_USER` and `ES_PASSWORD` or `ES_API_KEY`,
+    /// `ES_CLIENT_CERT` and `ES_CLIENT_KEY`, and `ES_INSECURE`, keeping credentials out of
+    /// the command lines
+    pub fn from_env() -> EsConfig {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        let mut config = EsConfig::new(&var("ES_ADDR").unwrap_or_else(|| ES_ADDR.to_string()));
+        config.auth = match (var("ES_API_KEY"), var("ES_USER")) {
+            (Some(key), _) => Some(EsAuth::ApiKey(key)),
+            (None, Some(user)) => Some(EsAuth::Basic {
+                user,
+                password: var("ES_PASSWORD").unwrap_or_default(),
+            }),
+            (None, None) => None,
+        };
+        config.client_certificate = var("ES_CLIENT_CERT").map(|cert| {
+            (
+                PathBuf::from(&cert),
+                PathBuf::from(var("ES_CLIENT_KEY").unwrap_or(cert)),
+            )
+        });
+        config.insecure = var("ES_INSECURE").is_some_and(|value| value == "1" || value == "true");
+        config
+    }
+}
+
+/// Version and health of the cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterHealth {
+    pub version: String,
+    /// green, yellow or red
+    pub status: String,
+}
+
+/// Differences between the mappings of the index and `es_mappings.json`, by field path
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MappingDiff {
+    /// Fields to add, which can be done in place
+    pub missing: Vec<String>,
+    /// Fields whose type or analyzer changed, which requires reindexing
+    pub changed: Vec<String>,
+    /// Fields of the index absent from the file
+    pub extra: Vec<String>,
 }
 
-fn index_exist(es_addr: String, client: &HttpClient) -> Result<Index, Box<dyn Error>> {
-    let mut resp = client.get(es_addr + "/peertube_se")?;
-    let mut result = Ok(Index::IndexIsPresent);
-    if let Ok(json) = resp.json::<serde_json::Value>() {
-        if let Some(code) = json["code"].as_u64() {
-            if code == 404 {
-                result = Ok(Index::IndexIsMissing);
+impl MappingDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// Lists the fields of mappings with their type and analyzer, sub-fields included
+fn flatten_fields(properties: &Value, prefix: &str, fields: &mut BTreeMap<String, String>) {
+    if let Some(properties) = properties.as_object() {
+        for (name, field) in properties {
+            let path = format!("{}{}", prefix, name);
+            if field["properties"].is_object() {
+                flatten_fields(&field["properties"], &format!("{}.", path), fields);
+            } else {
+                fields.insert(
+                    path.clone(),
+                    format!(
+                        "{}{}",
+                        field["type"].as_str().unwrap_or("object"),
+                        field["analyzer"]
+                            .as_str()
+                            .map(|analyzer| format!(" ({})", analyzer))
+                            .unwrap_or_default()
+                    ),
+                );
             }
+            flatten_fields(&field["fields"], &format!("{}.", path), fields);
+        }
+    }
+}
+
+/// Compares the properties of installed mappings with the expected ones
+pub fn diff_mappings(expected: &Value, installed: &Value) -> MappingDiff {
+    let (mut expected_fields, mut installed_fields) = (BTreeMap::new(), BTreeMap::new());
+    flatten_fields(&expected["properties"], "", &mut expected_fields);
+    flatten_fields(&installed["properties"], "", &mut installed_fields);
+    let mut diff = MappingDiff::default();
+    for (path, kind) in &expected_fields {
+        match installed_fields.get(path) {
+            None => diff.missing.push(path.clone()),
+            Some(installed) if installed != kind => diff
+                .changed
+                .push(format!("{} ({} -> {})", path, installed, kind)),
+            Some(_) => (),
         }
     }
-    result
+    diff.extra = installed_fields
+        .keys()
+        .filter(|path| !expected_fields.contains_key(*path))
+        .cloned()
+        .collect();
+    diff
+}
+
+/// Name of the index using a version of the mappings
+pub fn index_name(version: u64) -> String {
+    format!("{}_v{}", INDEX_ALIAS, version)
+}
+
+/// Version of mappings, 1 for the mappings predating versioning
+pub fn mappings_version(mappings: &Value) -> u64 {
+    mappings["_meta"]["version"].as_u64().unwrap_or(1)
+}
+
+/// Analyzed language of a video, from its PeerTube language code such as `pt-br`
+pub fn analyzed_language(video: &Video) -> Option<&'static str> {
+    let code = video.language.id.as_deref()?;
+    let code = code.split('-').next().unwrap_or(code).to_lowercase();
+    ANALYZED_LANGUAGES
+        .iter()
+        .find(|language| **language == code)
+        .copied()
+}
+
+/// Builds the indexed document of a video. Its name and description are copied into the fields
+/// analyzed for its language, if supported.
+pub fn to_document(video: &Video) -> Result<Value, serde_json::Error> {
+    let mut document = serde_json::to_value(video)?;
+    if let Some(language) = analyzed_language(video) {
+        document["i18n"] = json!({
+            language: {
+                "name": video.name,
+                "description": video.description
+            }
+        });
+    }
+    Ok(document)
+}
+
+fn check_acknowledged(json: &Value, action: &str) -> Result<(), EsError> {
+    // The expected answer is :
+    // {
+    //   "acknowledged": true,
+    //   "shards_acknowledged": true,
+    //   "index": "mapping_test"
+    // }
+    match json["acknowledged"].as_bool() {
+        Some(true) => Ok(()),
+        _ => Err(EsError::InvalidResponse(format!(
+            "failed to {} : {}",
+            action, json
+        ))),
+    }
+}
+
+/// Actions moving the alias from an index to another. An index predating the aliases has the
+/// name of the alias, and is deleted in the same step.
+fn swap_actions(source: &str, target: &str, legacy: bool) -> Value {
+    let remove = if legacy {
+        json!({ "remove_index": { "index": source } })
+    } else {
+        json!({ "remove": { "index": source, "alias": INDEX_ALIAS } })
+    };
+    json!({
+        "actions": [
+            remove,
+            { "add": { "index": target, "alias": INDEX_ALIAS } }
+        ]
+    })
+}
+
+/// Copies the language of the video into the analyzed fields, as `to_document` does
+const REINDEX_SCRIPT: &str = "\
+    def language = ctx._source.language == null ? null : ctx._source.language.id;\
+    if (language != null) {\
+        language = language.splitOnToken('-')[0].toLowerCase();\
+        if (params.languages.contains(language)) {\
+            ctx._source.i18n = [language: ['name': ctx._source.name,\
+                'description': ctx._source.description]];\
+        }\
+    }";
+
+#[derive(Debug, Clone)]
+pub struct ReindexSummary {
+    pub source: String,
+    pub target: String,
+    pub videos: u64,
+}
+
+/// A client of the cluster, authenticating every request
+pub struct Elastic {
+    addr: String,
+    client: HttpClient,
+    authorization: Option<String>,
 }
 
-/// Creates the elastic search mapping for Peertube videos
-pub fn create_mappings(es_addr: String, client: HttpClient) -> Result<(), Box<dyn Error>> {
-    if let Index::IndexIsMissing = index_exist(es_addr.clone(), &client)? {
-        let file = File::open(&es_addr)?;
-        let mut buf_reader = BufReader::new(file);
-        let mut mappings = String::new();
-        buf_reader.read_to_string(&mut mappings)?;
+impl Elastic {
+    pub fn new(config: &EsConfig) -> Result<Elastic, EsError> {
+        if !config.addr.starts_with("http://") && !config.addr.starts_with("https://") {
+            return Err(EsError::Config(format!(
+                "{} is not an http(s) address",
+                config.addr
+            )));
+        }
+        let mut builder = HttpClient::builder().danger_allow_unsafe_ssl(config.insecure);
+        if let Some((cert, key)) = &config.client_certificate {
+            builder = builder.ssl_client_certificate(ClientCertificate::PEM {
+                path: cert.clone(),
+                private_key: Some(PrivateKey::PEM {
+                    path: key.clone(),
+                    password: None,
+                }),
+            });
+        }
+        Ok(Elastic {
+            addr: config.addr.clone(),
+            client: builder.build()?,
+            authorization: config.auth.as_ref().map(EsAuth::header),
+        })
+    }
+
+    fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: &str,
+        body: Body,
+    ) -> Result<Response<Body>, EsError> {
+        let mut request = Request::builder();
+        request
+            .method(method)
+            .uri(format!("{}{}", self.addr, path))
+            .header("Content-Type", content_type);
+        if let Some(authorization) = &self.authorization {
+            request.header("Authorization", authorization.as_str());
+        }
+        Ok(self.client.send(request.body(body)?)?)
+    }
+
+    /// Sends a request, failing on error statuses and non JSON answers
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: &str,
+        body: Body,
+    ) -> Result<Value, EsError> {
+        let mut resp = self.send(method, path, content_type, body)?;
+        let text = resp
+            .text()
+            .map_err(|e| EsError::InvalidResponse(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(EsError::Status {
+                status: resp.status(),
+                body: text,
+            });
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn get(&self, path: &str) -> Result<Value, EsError> {
+        self.request(Method::GET, path, "application/json", Body::empty())
+    }
+
+    /// Returns None when the path does not exist
+    pub fn get_optional(&self, path: &str) -> Result<Option<Value>, EsError> {
+        match self.get(path) {
+            Err(EsError::Status { status, .. }) if status == StatusCode::NOT_FOUND => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    pub fn post(&self, path: &str, body: &Value) -> Result<Value, EsError> {
+        self.request(
+            Method::POST,
+            path,
+            "application/json",
+            Body::from(body.to_string()),
+        )
+    }
+
+    pub fn put(&self, path: &str, body: &Value) -> Result<Value, EsError> {
+        self.request(
+            Method::PUT,
+            path,
+            "application/json",
+            Body::from(body.to_string()),
+        )
+    }
+
+    pub fn delete(&self, path: &str, body: &Value) -> Result<Value, EsError> {
+        self.request(
+            Method::DELETE,
+            path,
+            "application/json",
+            Body::from(body.to_string()),
+        )
+    }
+
+    /// Sends newline delimited JSON, as expected by the bulk API
+    pub fn post_ndjson(&self, path: &str, body: String) -> Result<Value, EsError> {
+        self.request(Method::POST, path, "application/x-ndjson", Body::from(body))
+    }
+
+    /// Checks that the cluster is reachable, not red, and recent enough
+    pub fn health(&self) -> Result<ClusterHealth, EsError> {
+        let info = self.get("/")?;
+        let version = info["version"]["number"]
+            .as_str()
+            .ok_or_else(|| EsError::InvalidResponse(format!("no version in {}", info)))?
+            .to_string();
+        let major: u64 = version
+            .split('.')
+            .next()
+            .and_then(|major| major.parse().ok())
+            .unwrap_or(0);
+        if major < MIN_ES_VERSION {
+            return Err(EsError::Unhealthy(format!(
+                "version {} is not supported, {} or later is required",
+                version, MIN_ES_VERSION
+            )));
+        }
+        let health = self.get("/_cluster/health")?;
+        let status = health["status"].as_str().unwrap_or("unknown").to_string();
+        if status == "red" {
+            return Err(EsError::Unhealthy(format!("the cluster is {}", status)));
+        }
+        Ok(ClusterHealth { version, status })
+    }
+
+    /// Whether an index or an alias exists
+    pub fn index_exists(&self, index: &str) -> Result<bool, EsError> {
+        let resp = self.send(
+            Method::HEAD,
+            &format!("/{}", index),
+            "application/json",
+            Body::empty(),
+        )?;
+        match resp.status() {
+            StatusCode::OK => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => Err(EsError::Status {
+                status,
+                body: String::new(),
+            }),
+        }
+    }
+
+    /// Compares the mappings of the index with `es_mappings.json`
+    pub fn mapping_diff(&self) -> Result<MappingDiff, EsError> {
+        let expected: Value = serde_json::from_str(MAPPINGS)?;
+        let installed = self.get(&format!("/{}/_mapping", INDEX_ALIAS))?;
+        // The answer is keyed by the name of the index
+        let installed = installed
+            .as_object()
+            .and_then(|indices| indices.values().next())
+            .ok_or_else(|| EsError::InvalidResponse(installed.to_string()))?;
+        Ok(diff_mappings(&expected["mappings"], &installed["mappings"]))
+    }
 
+    /// Creates an index with the current mappings, optionally behind the alias
+    fn create_index(&self, index: &str, with_alias: bool) -> Result<(), EsError> {
+        let mut body: Value = serde_json::from_str(MAPPINGS)?;
+        if with_alias {
+            body["aliases"] = json!({ INDEX_ALIAS: {} });
+        }
         // Test me with curl :
         // `curl -X PUT localhost:9200/mapping_test2 -d "$(cat es_mappings.json)" -H "Content-Type: application/json`
-        let mut resp = client.put(es_addr + "/peertube_se", mappings)?;
-        if let Ok(json) = resp.json::<serde_json::Value>() {
-            // The expected answer is :
-            // {
-            //   "acknowledged": true,
-            //   "shards_acknowledged": true,
-            //   "index": "mapping_test"
-            // }
-            if let Some(acknowledged) = json["acknowledged"].as_bool() {
-                if !acknowledged {
-                    return Err(
-                        format!("Elastic Search failed to create mapping : {}", json).into(),
-                    );
-                }
+        let json = self.put(&format!("/{}", index), &body)?;
+        check_acknowledged(&json, "create mapping")
+    }
+
+    /// Creates the elastic search mapping for Peertube videos, or adds the fields missing from
+    /// an index created with older mappings. Existing videos only get the new fields once
+    /// reindexed.
+    pub fn create_mappings(&self) -> Result<(), EsError> {
+        let mappings: Value = serde_json::from_str(MAPPINGS)?;
+        if !self.index_exists(INDEX_ALIAS)? {
+            let index = index_name(mappings_version(&mappings["mappings"]));
+            return self.create_index(&index, true);
+        }
+        let diff = self.mapping_diff()?;
+        if !diff.changed.is_empty() {
+            return Err(EsError::MappingConflict(diff.changed));
+        }
+        if !diff.missing.is_empty() {
+            warn!(
+                "Adding {} to the mappings, videos indexed before get them once reindexed with \
+                 `indexer --reindex`",
+                diff.missing.join(", ")
+            );
+            let json = self.put(&format!("/{}/_mapping", INDEX_ALIAS), &mappings["mappings"])?;
+            check_acknowledged(&json, "update mapping")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the index behind the alias, None if `peertube_se` is a plain index created before
+    /// the aliases
+    fn alias_target(&self) -> Result<Option<String>, EsError> {
+        Ok(self
+            .get_optional(&format!("/_alias/{}", INDEX_ALIAS))?
+            .and_then(|json| {
+                json.as_object()
+                    .and_then(|indices| indices.keys().next().cloned())
+            }))
+    }
+
+    fn count(&self, index: &str) -> Result<u64, EsError> {
+        let json = self.get(&format!("/{}/_count", index))?;
+        json["count"]
+            .as_u64()
+            .ok_or_else(|| EsError::InvalidResponse(json.to_string()))
+    }
+
+    /// Blocks or allows the writes to an index
+    fn set_write_block(&self, index: &str, blocked: bool) -> Result<(), EsError> {
+        let json = self.put(
+            &format!("/{}/_settings", index),
+            &json!({ "index.blocks.write": blocked }),
+        )?;
+        check_acknowledged(&json, "set the write block")
+    }
+
+    /// Creates an index from the current mappings, copies the current index into it, and moves
+    /// the alias once both hold the same number of videos. The current index is read-only during
+    /// the copy, so that no video is lost. It is kept afterwards, to roll back by moving the alias
+    /// again, except for a plain `peertube_se` index created before the aliases, which is deleted
+    /// as the alias takes its name. Crawls must be stopped while reindexing, as the videos they
+    /// store are rejected until the alias moves.
+    pub fn reindex(&self) -> Result<ReindexSummary, EsError> {
+        let mappings: Value = serde_json::from_str(MAPPINGS)?;
+        let target = index_name(mappings_version(&mappings["mappings"]));
+        let (source, legacy) = match self.alias_target()? {
+            Some(index) => (index, false),
+            None if self.index_exists(INDEX_ALIAS)? => (INDEX_ALIAS.to_string(), true),
+            None => return Err(EsError::NothingToReindex),
+        };
+        if source == target {
+            return Err(EsError::AlreadyCurrent(source));
+        }
+        self.set_write_block(&source, true)?;
+        let result = self.copy_index(&source, &target, legacy);
+        // The legacy index no longer exists once the alias took its name
+        if result.is_err() || !legacy {
+            if let Err(e) = self.set_write_block(&source, false) {
+                warn!("Failed to allow the writes to {} again : {}", source, e);
             }
         }
+        result
+    }
+
+    /// Copies `source` into a new `target` index and moves the alias to it
+    fn copy_index(
+        &self,
+        source: &str,
+        target: &str,
+        legacy: bool,
+    ) -> Result<ReindexSummary, EsError> {
+        self.create_index(target, false)?;
+        let json = self.post(
+            "/_reindex?wait_for_completion=true&refresh=true",
+            &json!({
+                "source": { "index": source },
+                "dest": { "index": target },
+                "script": {
+                    "lang": "painless",
+                    "source": REINDEX_SCRIPT,
+                    "params": { "languages": ANALYZED_LANGUAGES }
+                }
+            }),
+        )?;
+        if !json["failures"].as_array().is_some_and(|f| f.is_empty()) {
+            return Err(EsError::InvalidResponse(format!(
+                "failed to reindex {} : {}",
+                source, json
+            )));
+        }
+        let (target_count, source_count) = (self.count(target)?, self.count(source)?);
+        if target_count != source_count {
+            return Err(EsError::CountMismatch {
+                source: source.to_string(),
+                source_count,
+                target: target.to_string(),
+                target_count,
+            });
+        }
+        let json = self.post("/_aliases", &swap_actions(source, target, legacy))?;
+        check_acknowledged(&json, "move the alias")?;
+        Ok(ReindexSummary {
+            source: source.to_string(),
+            target: target.to_string(),
+            videos: source_count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::elastic::{
+        diff_mappings, index_name, mappings_version, swap_actions, to_document, EsAuth,
+        ANALYZED_LANGUAGES, MAPPINGS,
+    };
+    use crate::peertube_api::Video;
+    use serde_json::json;
+
+    #[test]
+    fn elastic() {
+        let mappings: serde_json::Value = serde_json::from_str(MAPPINGS).unwrap();
+        assert!(mappings_version(&mappings["mappings"]) >= 2);
+        let i18n = &mappings["mappings"]["properties"]["i18n"]["properties"];
+        for language in ANALYZED_LANGUAGES {
+            assert!(
+                i18n[language]["properties"]["name"]["analyzer"].is_string(),
+                "{} is not mapped",
+                language
+            );
+        }
+
+        let mut video: Video = serde_json::from_str(include_str!("../tests/video1.json")).unwrap();
+        let document = to_document(&video).unwrap();
+        assert_eq!(document["i18n"]["fr"]["name"], video.name.as_str());
+        video.language.id = Some("pt-br".to_string());
+        assert!(to_document(&video).unwrap()["i18n"]["pt"].is_object());
+        video.language.id = None;
+        assert!(to_document(&video).unwrap().get("i18n").is_none());
+
+        assert_eq!(index_name(3), "peertube_se_v3");
+        let actions = swap_actions("peertube_se", "peertube_se_v2", true);
+        assert_eq!(
+            actions["actions"][0]["remove_index"]["index"],
+            "peertube_se"
+        );
+        assert_eq!(actions["actions"][1]["add"]["alias"], "peertube_se");
+    }
+
+    #[test]
+    fn mapping_diff() {
+        let mappings: serde_json::Value = serde_json::from_str(MAPPINGS).unwrap();
+        assert!(diff_mappings(&mappings["mappings"], &mappings["mappings"]).is_empty());
+
+        let installed = json!({ "properties": {
+            "name": { "type": "keyword" },
+            "legacy": { "type": "text" },
+            "account": { "properties": { "host": { "type": "text" } } }
+        }});
+        let diff = diff_mappings(&mappings["mappings"], &installed);
+        assert!(diff.missing.contains(&"i18n.fr.name".to_string()));
+        assert!(diff.missing.contains(&"account.host.keyword".to_string()));
+        assert_eq!(diff.changed, vec!["name (keyword -> text)"]);
+        assert_eq!(diff.extra, vec!["legacy"]);
+
+        let auth = EsAuth::Basic {
+            user: "elastic".to_string(),
+            password: "changeme".to_string(),
+        };
+        assert_eq!(auth.header(), "Basic ZWxhc3RpYzpjaGFuZ2VtZQ==");
     }
-    Ok(())
 }
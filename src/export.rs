@@ -0,0 +1,276 @@
+/// This module flattens the crawled videos into tables for data analysis tools
+use crate::peertube_api::Video;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::record::RecordWriter;
+use parquet_derive::ParquetRecordWriter;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A video, with its account, channel and labels flattened into columns
+#[derive(Serialize, Deserialize, ParquetRecordWriter, Debug, Clone)]
+pub struct VideoRecord {
+    pub host: String,
+    pub crawl_date: String,
+    pub uuid: String,
+    pub id: Option<i64>,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: String,
+    pub published_at: String,
+    pub updated_at: String,
+    pub originally_published_at: Option<String>,
+    pub duration: i64,
+    pub views: i64,
+    pub likes: i64,
+    pub dislikes: i64,
+    pub nsfw: bool,
+    pub hidden: bool,
+    pub is_local: bool,
+    pub category_id: Option<i64>,
+    pub category: String,
+    pub licence_id: Option<i64>,
+    pub licence: String,
+    pub language_id: Option<String>,
+    pub language: String,
+    pub privacy_id: Option<i64>,
+    pub privacy: String,
+    pub account_name: String,
+    pub account_display_name: String,
+    pub account_host: String,
+    pub account_url: String,
+    pub channel_name: String,
+    pub channel_display_name: String,
+    pub channel_host: String,
+    pub channel_url: String,
+    pub thumbnail_path: String,
+    pub embed_path: String,
+}
+
+impl VideoRecord {
+    pub fn new(video: &Video, host: &str, crawl_date: &str) -> VideoRecord {
+        VideoRecord {
+            host: host.to_string(),
+            crawl_date: crawl_date.to_string(),
+            uuid: video.uuid.clone(),
+            id: video.id,
+            name: video.name.clone(),
+            description: video.description.clone(),
+            created_at: video.created_at.clone(),
+            published_at: video.published_at.clone(),
+            updated_at: video.updated_at.clone(),
+            originally_published_at: video.originally_published_at.clone(),
+            duration: video.duration,
+            views: video.views,
+            likes: video.likes,
+            dislikes: video.dislikes,
+            nsfw: video.nsfw,
+            hidden: video.hidden,
+            is_local: video.is_local,
+            category_id: video.category.id,
+            category: video.category.label.clone(),
+            licence_id: video.licence.id,
+            licence: video.licence.label.clone(),
+            language_id: video.language.id.clone(),
+            language: video.language.label.clone(),
+            privacy_id: video.privacy.id,
+            privacy: video.privacy.label.clone(),
+            account_name: video.account.name.clone(),
+            account_display_name: video.account.display_name.clone(),
+            account_host: video.account.host.clone(),
+            account_url: video.account.url.clone(),
+            channel_name: video.channel.name.clone(),
+            channel_display_name: video.channel.display_name.clone(),
+            channel_host: video.channel.host.clone(),
+            channel_url: video.channel.url.clone(),
+            thumbnail_path: video.thumbnail_path.clone(),
+            embed_path: video.embed_path.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Parquet,
+    Csv,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Parquet => "parquet",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parquet" => Ok(Format::Parquet),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown format {} (expected parquet or csv)", s)),
+        }
+    }
+}
+
+/// How the exported records are split into directories
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Partition {
+    None,
+    Host,
+    Date,
+}
+
+impl Partition {
+    /// The Hive style directory (`host=framatube.org`) of the records of a crawl file
+    pub fn directory(self, host: &str, crawl_date: &str) -> Option<String> {
+        match self {
+            Partition::None => None,
+            Partition::Host => Some(format!("host={}", host)),
+            Partition::Date => Some(format!("crawl_date={}", crawl_date)),
+        }
+    }
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Partition::None),
+            "host" => Ok(Partition::Host),
+            "date" => Ok(Partition::Date),
+            _ => Err(format!(
+                "Unknown partitioning {} (expected none, host or date)",
+                s
+            )),
+        }
+    }
+}
+
+enum Sink {
+    Csv(csv::Writer<File>),
+    Parquet(SerializedFileWriter<File>),
+}
+
+/// Writes records to a file batch by batch, so that an export never holds more than one batch
+/// in memory. Each batch is a row group in Parquet files.
+pub struct RecordsWriter {
+    sink: Sink,
+    written: u64,
+}
+
+impl RecordsWriter {
+    pub fn create(path: &Path, format: Format) -> Result<RecordsWriter, Box<dyn Error>> {
+        let sink = match format {
+            Format::Csv => Sink::Csv(csv::Writer::from_path(path)?),
+            Format::Parquet => {
+                let empty: &[VideoRecord] = &[];
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Sink::Parquet(SerializedFileWriter::new(
+                    File::create(path)?,
+                    empty.schema()?,
+                    Arc::new(properties),
+                )?)
+            }
+        };
+        Ok(RecordsWriter { sink, written: 0 })
+    }
+
+    pub fn write(&mut self, records: &[VideoRecord]) -> Result<(), Box<dyn Error>> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        match &mut self.sink {
+            Sink::Csv(writer) => {
+                for record in records {
+                    writer.serialize(record)?;
+                }
+            }
+            Sink::Parquet(writer) => {
+                let mut row_group = writer.next_row_group()?;
+                records.write_to_row_group(&mut row_group)?;
+                row_group.close()?;
+            }
+        }
+        self.written += records.len() as u64;
+        Ok(())
+    }
+
+    /// Number of records written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn close(self) -> Result<(), Box<dyn Error>> {
+        match self.sink {
+            Sink::Csv(mut writer) => writer.flush()?,
+            Sink::Parquet(writer) => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::export::{Format, RecordsWriter, VideoRecord};
+    use crate::peertube_api::Video;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::fs::File;
+
+    #[test]
+    fn export() {
+        let json = include_str!("../tests/video1.json");
+        let video: Video = serde_json::from_str(json).unwrap();
+        let record = VideoRecord::new(&video, "peertube.example", "2020-05-01");
+        let dir = std::env::temp_dir().join(format!("peertube_se_export_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for format in &[Format::Csv, Format::Parquet] {
+            let path = dir.join(format!("videos.{}", format.extension()));
+            let mut writer = RecordsWriter::create(&path, *format).unwrap();
+            writer.write(std::slice::from_ref(&record)).unwrap();
+            writer.write(&[]).unwrap();
+            writer.write(&[record.clone(), record.clone()]).unwrap();
+            assert_eq!(writer.written(), 3);
+            writer.close().unwrap();
+            match format {
+                Format::Csv => {
+                    let read: Vec<VideoRecord> = csv::Reader::from_path(&path)
+                        .unwrap()
+                        .deserialize()
+                        .map(Result::unwrap)
+                        .collect();
+                    assert_eq!(read.len(), 3);
+                    assert_eq!(read[2].uuid, video.uuid);
+                    assert_eq!(read[2].description, video.description);
+                    assert_eq!(read[2].crawl_date, "2020-05-01");
+                }
+                Format::Parquet => {
+                    let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+                    assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+                    assert_eq!(reader.metadata().num_row_groups(), 2);
+                    let row = reader.get_row_iter(None).unwrap().next().unwrap().unwrap();
+                    let uuid = row
+                        .get_column_iter()
+                        .find(|(name, _)| *name == "uuid")
+                        .map(|(_, field)| field.to_string())
+                        .unwrap();
+                    assert_eq!(uuid, format!("\"{}\"", video.uuid));
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
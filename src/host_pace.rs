@@ -0,0 +1,127 @@
+/// This module adapts request timeouts and page sizes to the latency of each host, so that slow
+/// but alive instances are crawled completely
+use std::time::Duration;
+
+/** PeerTube refuses pages of more than 100 videos */
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+pub const MIN_PAGE_SIZE: u64 = 10;
+
+/** Weight of the latest request in the average latency */
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/** The timeout of a host is at least this multiple of its average latency */
+const TIMEOUT_LATENCY_FACTOR: f64 = 4.0;
+
+/** Pages slower than this fraction of their timeout shrink the page size */
+const SLOW_FRACTION: f64 = 0.5;
+
+/** Pages faster than this fraction of their timeout grow the page size */
+const FAST_FRACTION: f64 = 0.125;
+
+/// Base timeouts of the requests, by endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    /// Followers and following lists
+    pub follow: Duration,
+    pub videos: Duration,
+    /// Configuration and blocklist
+    pub other: Duration,
+    /// Limit of the timeouts raised for slow hosts
+    pub max: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            follow: Duration::from_secs(20),
+            videos: Duration::from_secs(60),
+            other: Duration::from_secs(15),
+            max: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Doubles a timeout after a request timed out
+pub fn escalate(timeout: Duration, max: Duration) -> Duration {
+    (timeout * 2).min(max)
+}
+
+/// Observed latency of a host, and the page size its videos are fetched with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HostPace {
+    /// Moving average of the response times, in milliseconds
+    pub latency_ms: Option<f64>,
+    pub page_size: u64,
+}
+
+impl Default for HostPace {
+    fn default() -> Self {
+        HostPace {
+            latency_ms: None,
+            page_size: MAX_PAGE_SIZE,
+        }
+    }
+}
+
+impl HostPace {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        self.latency_ms = Some(match self.latency_ms {
+            Some(average) => average + LATENCY_WEIGHT * (ms - average),
+            None => ms,
+        });
+    }
+
+    /// Timeout of a request to this host : the base timeout of the endpoint, raised for slow hosts
+    pub fn timeout(&self, base: Duration, max: Duration) -> Duration {
+        let latency = self.latency_ms.unwrap_or(0.0) * TIMEOUT_LATENCY_FACTOR;
+        base.max(Duration::from_millis(latency as u64)).min(max)
+    }
+
+    /// Shrinks the page size after a slow page, and grows it back after fast ones
+    pub fn adapt_page_size(&mut self, elapsed: Duration, timeout: Duration) {
+        let fraction = elapsed.as_secs_f64() / timeout.as_secs_f64();
+        if fraction > SLOW_FRACTION {
+            self.shrink_page_size();
+        } else if fraction < FAST_FRACTION {
+            self.page_size = (self.page_size * 2).min(MAX_PAGE_SIZE);
+        }
+    }
+
+    /// Halves the page size, returning false if it is already minimal
+    pub fn shrink_page_size(&mut self) -> bool {
+        if self.page_size <= MIN_PAGE_SIZE {
+            return false;
+        }
+        self.page_size = (self.page_size / 2).max(MIN_PAGE_SIZE);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::host_pace::{HostPace, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+    use std::time::Duration;
+
+    #[test]
+    fn host_pace() {
+        let mut pace = HostPace::default();
+        let timeout = Duration::from_secs(10);
+        pace.observe(Duration::from_secs(8));
+        assert_eq!(pace.latency_ms, Some(8000.0));
+        assert_eq!(
+            pace.timeout(timeout, Duration::from_secs(300)),
+            Duration::from_secs(32)
+        );
+
+        pace.adapt_page_size(Duration::from_secs(8), timeout);
+        assert_eq!(pace.page_size, MAX_PAGE_SIZE / 2);
+        while pace.shrink_page_size() {}
+        assert_eq!(pace.page_size, MIN_PAGE_SIZE);
+        pace.adapt_page_size(Duration::from_millis(100), timeout);
+        assert_eq!(pace.page_size, MIN_PAGE_SIZE * 2);
+    }
+}
@@ -0,0 +1,15 @@
+/// This module holds helpers shared by the HTML reports
+use handlebars::{Handlebars, RenderError};
+use serde::Serialize;
+
+/// Renders a report template, which defines an inline `page` partial wrapped by `{{> layout}}`
+pub fn render_report<T: Serialize>(template: &str, data: &T) -> Result<String, RenderError> {
+    let mut templates = Handlebars::new();
+    templates.set_strict_mode(true);
+    templates.register_template_string(
+        "layout",
+        include_str!("../templates/reports/layout.html.hbs"),
+    )?;
+    templates.register_template_string("report", template)?;
+    templates.render("report", data)
+}
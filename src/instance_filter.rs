@@ -0,0 +1,163 @@
+/// This module decides which instances may be crawled and indexed
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// A host name pattern, where `*` matches any sequence of characters (`*.example.com`)
+#[derive(Debug, Clone)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    pub fn new(pattern: &str) -> HostPattern {
+        HostPattern(pattern.trim().to_lowercase())
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        wildcard_match(self.0.as_bytes(), host.to_lowercase().as_bytes())
+    }
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    /// Blocked by the operator deny list, which holds across runs
+    Denied(String),
+    /// Left out of this run only, by the allow list or an imported blocklist
+    Excluded(String),
+}
+
+/// Allow and deny lists of host patterns.
+/// When the allow list is empty, every host that is not denied is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct InstanceFilter {
+    allow: Vec<HostPattern>,
+    deny: Vec<(HostPattern, String)>,
+    exclude: Vec<(HostPattern, String)>,
+}
+
+impl InstanceFilter {
+    pub fn new() -> InstanceFilter {
+        InstanceFilter::default()
+    }
+
+    pub fn allow(&mut self, pattern: &str) {
+        self.allow.push(HostPattern::new(pattern));
+    }
+
+    pub fn deny(&mut self, pattern: &str, reason: String) {
+        self.deny.push((HostPattern::new(pattern), reason));
+    }
+
+    /// Excludes hosts for the current run only, e.g. from a blocklist imported from an instance
+    pub fn exclude(&mut self, pattern: &str, reason: String) {
+        self.exclude.push((HostPattern::new(pattern), reason));
+    }
+
+    /// Loads an allow list : one pattern per line, `#` starts a comment
+    pub fn load_allowlist(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        for (pattern, _) in read_list(path)? {
+            self.allow(&pattern);
+        }
+        Ok(())
+    }
+
+    /// Loads a deny list : one pattern per line, optionally followed by the reason of the block
+    pub fn load_denylist(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        for (pattern, reason) in read_list(path)? {
+            self.deny(&pattern, reason.unwrap_or_else(|| "denylist".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, host: &str) -> Verdict {
+        if let Some((_, reason)) = self.deny.iter().find(|(p, _)| p.matches(host)) {
+            return Verdict::Denied(reason.clone());
+        }
+        if let Some((_, reason)) = self.exclude.iter().find(|(p, _)| p.matches(host)) {
+            return Verdict::Excluded(reason.clone());
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| p.matches(host)) {
+            return Verdict::Excluded("not in allowlist".to_string());
+        }
+        Verdict::Allowed
+    }
+
+    pub fn is_allowed(&self, host: &str) -> bool {
+        self.check(host) == Verdict::Allowed
+    }
+}
+
+/// A pattern and the optional text that follows it on the same line
+type ListEntry = (String, Option<String>);
+
+fn read_list(path: &Path) -> Result<Vec<ListEntry>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut result = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let pattern = parts.next().unwrap_or("").to_string();
+        let reason = parts
+            .next()
+            .map(|r| r.trim().to_string())
+            .filter(|r| !r.is_empty());
+        result.push((pattern, reason));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::instance_filter::{InstanceFilter, Verdict};
+
+    #[test]
+    fn instance_filter() {
+        let mut filter = InstanceFilter::new();
+        filter.deny("*.spam.example", "spam".to_string());
+        assert_eq!(
+            filter.check("videos.SPAM.example"),
+            Verdict::Denied("spam".to_string())
+        );
+        assert!(!filter.is_allowed("spam.example.org.spam.example"));
+        assert!(filter.is_allowed("spam.example"));
+
+        filter.exclude("blocked.example", "blocked by peertube.social".to_string());
+        assert_eq!(
+            filter.check("blocked.example"),
+            Verdict::Excluded("blocked by peertube.social".to_string())
+        );
+
+        filter.allow("peertube.*");
+        assert!(filter.is_allowed("peertube.social"));
+        assert_eq!(
+            filter.check("framatube.org"),
+            Verdict::Excluded("not in allowlist".to_string())
+        );
+    }
+}
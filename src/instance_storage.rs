@@ -1,5 +1,11 @@
+use crate::crawl_output::ResumePoint;
+use crate::host_pace::HostPace;
+use crate::ranking::InstanceReputation;
+use crate::schedule::{Activity, PARTIAL_RECRAWL_HOURS};
+use chrono::{DateTime, Duration, Utc};
 use log::warn;
-use rusqlite::{Connection, NO_PARAMS};
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::BTreeSet;
 pub struct InstanceDb {
     conn: Connection,
     new_instance_inserted: u32,
@@ -12,20 +18,59 @@ impl Default for InstanceDb {
 }
 
 impl InstanceDb {
+    /// Same as `open`, panicking on failure
     pub fn new() -> InstanceDb {
-        let conn = Connection::open("instances.db").expect("Failed to open DB");
+        InstanceDb::open().expect("Failed to open DB")
+    }
+
+    /// Opens `instances.db`, creating the tables and columns it lacks
+    pub fn open() -> rusqlite::Result<InstanceDb> {
+        let conn = Connection::open("instances.db")?;
         conn.execute(
             "create table if not exists peertube_instances (
              id integer primary key,
              base_url text not null unique
          )",
             NO_PARAMS,
-        )
-        .expect("Failed to create table");
-        InstanceDb {
+        )?;
+        // Databases created before the deny lists and the recrawl schedule existed lack these
+        // columns
+        for column in &[
+            "blocked_reason text",
+            "last_crawled integer",
+            "last_published_at text",
+            "next_crawl integer",
+            "resume_index integer",
+            "resume_lines integer",
+            "latency_ms real",
+            "page_size integer",
+            "reachable integer",
+            "video_count integer",
+        ] {
+            let _ = conn.execute(
+                &format!("alter table peertube_instances add column {}", column),
+                NO_PARAMS,
+            );
+        }
+        conn.execute(
+            "create table if not exists instance_sources (
+             base_url text not null,
+             source text not null,
+             primary key (base_url, source)
+         )",
+            NO_PARAMS,
+        )?;
+        conn.execute(
+            "create table if not exists crawler_state (
+             key text primary key,
+             value text not null
+         )",
+            NO_PARAMS,
+        )?;
+        Ok(InstanceDb {
             conn,
             new_instance_inserted: 0,
-        }
+        })
     }
 
     pub fn insert_instance(&mut self, instance: String) {
@@ -38,10 +83,60 @@ impl InstanceDb {
         }
     }
 
+    /// Records an instance along with the seed sources listing it
+    pub fn insert_seed(&mut self, instance: &str, sources: &BTreeSet<String>) {
+        self.insert_instance(instance.to_string());
+        for source in sources {
+            if let Err(e) = self.conn.execute(
+                "insert or ignore into instance_sources (base_url, source) values (?1, ?2)",
+                &[instance, source],
+            ) {
+                warn!("Failed to record the source of {} : {}", instance, e);
+            }
+        }
+    }
+
+    /// Records an instance that must not be crawled, along with the reason of the block
+    pub fn insert_blocked_instance(&mut self, instance: String, reason: String) {
+        match self.conn.execute(
+            "insert into peertube_instances (base_url, blocked_reason) values (?1, ?2)
+             on conflict(base_url) do update set blocked_reason = excluded.blocked_reason",
+            params![instance, reason],
+        ) {
+            Ok(_) => (),
+            Err(e) => warn!("Failed to insert blocked instance into database : {}", e),
+        }
+    }
+
+    /// Recomputes the blocked instances from the deny list of this run, `denied` returning the
+    /// reason of the block of an instance, so that hosts removed from the list are crawled again
+    pub fn refresh_blocked_instances<F: Fn(&str) -> Option<String>>(&mut self, denied: F) {
+        let result = self.conn.transaction().and_then(|tx| {
+            let instances = {
+                let mut stmt = tx.prepare("select base_url from peertube_instances")?;
+                let instance_iter = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))?;
+                instance_iter
+                    .filter_map(Result::ok)
+                    .collect::<Vec<String>>()
+            };
+            for instance in instances {
+                tx.execute(
+                    "update peertube_instances set blocked_reason = ?2 where base_url = ?1",
+                    params![instance, denied(&instance)],
+                )?;
+            }
+            tx.commit()
+        });
+        if let Err(e) = result {
+            warn!("Failed to refresh the blocked instances : {}", e);
+        }
+    }
+
+    /// Returns every instance that is not blocked
     pub fn get_all_instances(&self) -> Vec<String> {
         let mut stmt = self
             .conn
-            .prepare("select base_url from peertube_instances")
+            .prepare("select base_url from peertube_instances where blocked_reason is null")
             .unwrap();
         let instance_iter = stmt
             .query_map(NO_PARAMS, |row| Ok(row.get(0).unwrap()))
@@ -51,6 +146,207 @@ impl InstanceDb {
             .collect::<Vec<String>>()
     }
 
+    /// Records the end of the crawl of an instance, and schedules its next crawl from its activity.
+    /// `latest_published_at` is the date of the most recent video seen, if any. Unreachable
+    /// instances are considered dormant.
+    pub fn record_crawl(
+        &mut self,
+        instance: &str,
+        reachable: bool,
+        latest_published_at: Option<&str>,
+        now: DateTime<Utc>,
+    ) {
+        let (previous, resume_index): (Option<String>, Option<i64>) = self
+            .conn
+            .query_row(
+                "select last_published_at, resume_index from peertube_instances
+                 where base_url = ?1",
+                &[instance],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap_or(None)
+            .unwrap_or((None, None));
+        let latest = match (latest_published_at, previous.as_deref()) {
+            (Some(new), Some(old)) => Some(new.max(old)),
+            (new, old) => new.or(old),
+        };
+        let activity = if reachable {
+            Activity::new(latest, now)
+        } else {
+            Activity::Dormant
+        };
+        // Partial crawls are resumed soon, whatever the activity
+        let next_crawl = match resume_index {
+            Some(_) => now + Duration::hours(PARTIAL_RECRAWL_HOURS),
+            None => now + activity.recrawl_interval(),
+        };
+        match self.conn.execute(
+            "insert into peertube_instances (base_url, last_crawled, last_published_at, next_crawl)
+             values (?1, ?2, ?3, ?4)
+             on conflict(base_url) do update set last_crawled = excluded.last_crawled,
+             last_published_at = excluded.last_published_at, next_crawl = excluded.next_crawl",
+            params![instance, now.timestamp(), latest, next_crawl.timestamp()],
+        ) {
+            Ok(_) => (),
+            Err(e) => warn!("Failed to record crawl of {} : {}", instance, e),
+        }
+    }
+
+    /// Returns the instances that are not blocked and whose next crawl is due, never crawled
+    /// instances included
+    pub fn get_due_instances(&self, now: DateTime<Utc>) -> Vec<String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "select base_url from peertube_instances where blocked_reason is null
+                 and (next_crawl is null or next_crawl <= ?1) order by next_crawl",
+            )
+            .unwrap();
+        let instance_iter = stmt
+            .query_map([now.timestamp()], |row| Ok(row.get(0).unwrap()))
+            .unwrap();
+        instance_iter
+            .filter_map(Result::ok)
+            .collect::<Vec<String>>()
+    }
+
+    /// Makes the given instances due again unless they were crawled since `since`, so that an
+    /// interrupted crawl resumes where it stopped
+    pub fn checkpoint(&mut self, pending: &[String], since: DateTime<Utc>) {
+        let result = self.conn.transaction().and_then(|tx| {
+            for instance in pending {
+                tx.execute(
+                    "update peertube_instances set next_crawl = ?2
+                     where base_url = ?1 and (last_crawled is null or last_crawled < ?2)",
+                    params![instance, since.timestamp()],
+                )?;
+            }
+            tx.commit()
+        });
+        if let Err(e) = result {
+            warn!("Failed to checkpoint the crawl : {}", e);
+        }
+    }
+
+    /// Returns where the last crawl of an instance stopped, if it failed midway
+    pub fn get_resume_point(&self, instance: &str) -> Option<ResumePoint> {
+        self.conn
+            .query_row(
+                "select resume_index, resume_lines from peertube_instances where base_url = ?1",
+                &[instance],
+                |row| {
+                    // Crawls stopped before the written videos were counted start over
+                    let offset: Option<i64> = row.get(0)?;
+                    let lines: Option<i64> = row.get(1)?;
+                    Ok(offset.zip(lines).map(|(offset, lines)| ResumePoint {
+                        offset: offset as u64,
+                        lines: lines as u64,
+                    }))
+                },
+            )
+            .optional()
+            .unwrap_or(None)
+            .flatten()
+    }
+
+    pub fn set_resume_point(&mut self, instance: &str, point: Option<ResumePoint>) {
+        match self.conn.execute(
+            "update peertube_instances set resume_index = ?2, resume_lines = ?3
+             where base_url = ?1",
+            params![
+                instance,
+                point.map(|point| point.offset as i64),
+                point.map(|point| point.lines as i64)
+            ],
+        ) {
+            Ok(_) => (),
+            Err(e) => warn!("Failed to record partial crawl of {} : {}", instance, e),
+        }
+    }
+
+    /// Returns the latency and page size observed during the last crawl of an instance
+    pub fn get_host_pace(&self, instance: &str) -> HostPace {
+        self.conn
+            .query_row(
+                "select latency_ms, page_size from peertube_instances where base_url = ?1",
+                &[instance],
+                |row| {
+                    Ok(HostPace {
+                        latency_ms: row.get(0)?,
+                        page_size: row
+                            .get::<_, Option<i64>>(1)?
+                            .map(|size| size as u64)
+                            .unwrap_or(HostPace::default().page_size),
+                    })
+                },
+            )
+            .optional()
+            .unwrap_or(None)
+            .unwrap_or_default()
+    }
+
+    pub fn set_host_pace(&mut self, instance: &str, pace: &HostPace) {
+        match self.conn.execute(
+            "update peertube_instances set latency_ms = ?2, page_size = ?3 where base_url = ?1",
+            params![instance, pace.latency_ms, pace.page_size as i64],
+        ) {
+            Ok(_) => (),
+            Err(e) => warn!("Failed to record latency of {} : {}", instance, e),
+        }
+    }
+
+    /// Records whether an instance answered its last crawl, and its number of videos. The number
+    /// of videos of unreachable instances is kept from their last successful crawl.
+    pub fn set_reputation(&mut self, instance: &str, reachable: bool, videos: u64) {
+        match self.conn.execute(
+            "update peertube_instances set reachable = ?2,
+             video_count = case when ?2 then ?3 else video_count end where base_url = ?1",
+            params![instance, reachable, videos as i64],
+        ) {
+            Ok(_) => (),
+            Err(e) => warn!("Failed to record reputation of {} : {}", instance, e),
+        }
+    }
+
+    /// Returns the health and size of the crawled instances that are not blocked
+    pub fn get_reputations(&self) -> rusqlite::Result<Vec<InstanceReputation>> {
+        let mut stmt = self.conn.prepare(
+            "select base_url, reachable, video_count from peertube_instances
+             where blocked_reason is null and reachable is not null",
+        )?;
+        let reputation_iter = stmt.query_map(NO_PARAMS, |row| {
+            Ok(InstanceReputation {
+                host: row.get(0)?,
+                healthy: row.get(1)?,
+                videos: row.get::<_, Option<i64>>(2)?.unwrap_or(0) as u64,
+            })
+        })?;
+        Ok(reputation_iter.filter_map(Result::ok).collect())
+    }
+
+    pub fn get_state(&self, key: &str) -> Option<String> {
+        self.conn
+            .query_row(
+                "select value from crawler_state where key = ?1",
+                &[key],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap_or(None)
+    }
+
+    pub fn set_state(&mut self, key: &str, value: &str) {
+        match self.conn.execute(
+            "insert into crawler_state (key, value) values (?1, ?2)
+             on conflict(key) do update set value = excluded.value",
+            &[key, value],
+        ) {
+            Ok(_) => (),
+            Err(e) => warn!("Failed to save crawler state : {}", e),
+        }
+    }
+
     pub fn get_instance_added(&self) -> u32 {
         self.new_instance_inserted
     }
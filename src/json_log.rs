@@ -0,0 +1,181 @@
+/// This module logs records as JSON lines on stderr, for log collectors.
+/// Messages logged with `host_log` or `endpoint_log` get their host and endpoint as separate
+/// fields.
+use chrono::Utc;
+use log::kv::Key;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LogLine<'a> {
+    pub ts: String,
+    pub level: String,
+    pub target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<&'a str>,
+    pub message: &'a str,
+}
+
+/// Logs a message about a host, which reads `[host] message` on stderr while the JSON logger
+/// gets the host as a separate field
+#[macro_export]
+macro_rules! host_log {
+    ($level:expr, $host:expr, $($arg:tt)+) => {
+        ::log::log!($level, host = &*$host; "[{}] {}", $host, format_args!($($arg)+))
+    };
+}
+
+/// Logs a message about an endpoint of a host, as `[host][endpoint] message` on stderr
+#[macro_export]
+macro_rules! endpoint_log {
+    ($level:expr, $host:expr, $endpoint:expr, $($arg:tt)+) => {
+        ::log::log!(
+            $level,
+            host = &*$host,
+            endpoint = &*$endpoint;
+            "[{}][{}] {}",
+            $host,
+            $endpoint,
+            format_args!($($arg)+)
+        )
+    };
+}
+
+/// Removes the `[value]` prefix added by `host_log` and `endpoint_log` to a message
+fn strip_field<'a>(message: &'a str, value: &Option<String>) -> &'a str {
+    match value {
+        Some(value) => message
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_prefix(value.as_str()))
+            .and_then(|rest| rest.strip_prefix(']'))
+            .unwrap_or(message),
+        None => message,
+    }
+}
+
+/// Formats a record as a JSON line
+fn json_line(record: &Record) -> Option<String> {
+    let field = |key: &str| {
+        record
+            .key_values()
+            .get(Key::from_str(key))
+            .map(|value| value.to_string())
+    };
+    let (host, endpoint) = (field("host"), field("endpoint"));
+    let message = record.args().to_string();
+    let stripped = strip_field(strip_field(&message, &host), &endpoint);
+    let line = LogLine {
+        ts: Utc::now().to_rfc3339(),
+        level: record.level().to_string(),
+        target: record.target(),
+        host: host.as_deref(),
+        endpoint: endpoint.as_deref(),
+        message: stripped.trim_start(),
+    };
+    serde_json::to_string(&line).ok()
+}
+
+pub struct JsonLogger {
+    level: LevelFilter,
+    modules: Vec<String>,
+}
+
+impl JsonLogger {
+    /// Logs the records of the given modules up to `level`
+    pub fn init(level: LevelFilter, modules: &[&str]) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(JsonLogger {
+            level,
+            modules: modules.iter().map(|m| m.to_string()).collect(),
+        }))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && self
+                .modules
+                .iter()
+                .any(|module| metadata.target().starts_with(module.as_str()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(json) = json_line(record) {
+            eprintln!("{}", json);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use crate::json_log::json_line;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use serde_json::Value;
+    use std::sync::Mutex;
+
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LINES.lock().unwrap().extend(json_line(record));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn logged(host: &str) -> Value {
+        let lines = LINES.lock().unwrap();
+        let line = lines.iter().rev().find(|line| line.contains(host)).unwrap();
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn host_fields() {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+
+        let name = "json-log.example".to_string();
+        let url = format!("https://{}/api/v1/videos", name);
+        endpoint_log!(
+            Level::Trace,
+            name,
+            "/videos/",
+            "Failed to fetch videos from {} : {}",
+            url,
+            503
+        );
+        let line = logged(&name);
+        assert_eq!(line["host"], "json-log.example");
+        assert_eq!(line["endpoint"], "/videos/");
+        assert_eq!(
+            line["message"],
+            "Failed to fetch videos from https://json-log.example/api/v1/videos : 503"
+        );
+
+        endpoint_log!(Level::Trace, name, "webfinger", "Failed : timeout");
+        let line = logged(&name);
+        assert_eq!(line["endpoint"], "webfinger");
+        assert_eq!(line["message"], "Failed : timeout");
+
+        host_log!(Level::Info, name, "Imported {} blocked servers", 2);
+        let line = logged(&name);
+        assert_eq!(line["host"], "json-log.example");
+        assert!(line.get("endpoint").is_none());
+        assert_eq!(line["message"], "Imported 2 blocked servers");
+    }
+}
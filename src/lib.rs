@@ -1,3 +1,27 @@
+pub mod activitypub;
+pub mod consistency;
+pub mod crawl_limits;
+pub mod crawl_output;
+pub mod crawl_report;
 pub mod elastic;
+pub mod export;
+pub mod host_pace;
+pub mod html;
+pub mod instance_filter;
 pub mod instance_storage;
+pub mod json_log;
+pub mod metrics;
+pub mod mirrors;
 pub mod peertube_api;
+pub mod progress;
+pub mod ranking;
+pub mod retry;
+pub mod schedule;
+pub mod search;
+pub mod seed_sources;
+pub mod sqlite_storage;
+pub mod thumbnails;
+pub mod validation;
+pub mod video_policy;
+pub mod video_storage;
+pub mod web;
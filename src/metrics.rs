@@ -0,0 +1,123 @@
+/// This module exposes the progress of a crawl as Prometheus metrics
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Response, Server};
+
+/** Upper bounds of the request latency buckets, in seconds */
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+pub struct CrawlMetrics {
+    registry: Registry,
+    /// Requests by endpoint and HTTP status, or error kind when no complete response was received
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub bytes_fetched: IntCounter,
+    pub videos_parsed: IntCounter,
+    pub videos_failed: IntCounter,
+    pub instances_queued: IntCounter,
+    pub instances_done: IntCounter,
+    /// Instances which never answered a request
+    pub instances_failed: IntCounter,
+}
+
+impl CrawlMetrics {
+    pub fn new() -> Result<CrawlMetrics, prometheus::Error> {
+        let registry = Registry::new_custom(Some("peertube_crawler".to_string()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "HTTP requests sent to the instances"),
+            &["endpoint", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Latency of the HTTP requests")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["endpoint"],
+        )?;
+        let bytes_fetched = IntCounter::new("bytes_fetched_total", "Size of the response bodies")?;
+        let videos_parsed = IntCounter::new("videos_parsed_total", "Videos parsed")?;
+        let videos_failed = IntCounter::new("videos_failed_total", "Videos failing to parse")?;
+        let instances_queued = IntCounter::new("instances_queued_total", "Instances queued")?;
+        let instances_done = IntCounter::new("instances_done_total", "Instances crawled")?;
+        let instances_failed = IntCounter::new(
+            "instances_failed_total",
+            "Instances which could not be reached",
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(bytes_fetched.clone()))?;
+        registry.register(Box::new(videos_parsed.clone()))?;
+        registry.register(Box::new(videos_failed.clone()))?;
+        registry.register(Box::new(instances_queued.clone()))?;
+        registry.register(Box::new(instances_done.clone()))?;
+        registry.register(Box::new(instances_failed.clone()))?;
+        Ok(CrawlMetrics {
+            registry,
+            requests,
+            request_duration,
+            bytes_fetched,
+            videos_parsed,
+            videos_failed,
+            instances_queued,
+            instances_done,
+            instances_failed,
+        })
+    }
+
+    /// Records a request, `status` being the HTTP status or the kind of error
+    pub fn observe_request(&self, endpoint: &str, status: &str, seconds: f64) {
+        self.requests.with_label_values(&[endpoint, status]).inc();
+        self.request_duration
+            .with_label_values(&[endpoint])
+            .observe(seconds);
+    }
+
+    /// Renders the metrics in the Prometheus text format
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).to_string())
+    }
+}
+
+/// Serves the metrics on `http://<addr>/metrics` from a background thread
+pub fn serve_metrics(
+    addr: &str,
+    metrics: Arc<CrawlMetrics>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let server = Server::http(addr)?;
+    thread::spawn(move || {
+        let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                match metrics.render() {
+                    Ok(text) => Response::from_string(text).with_header(content_type.clone()),
+                    Err(e) => Response::from_string(e.to_string()).with_status_code(500),
+                }
+            } else {
+                Response::from_string("Not found").with_status_code(404)
+            };
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::CrawlMetrics;
+
+    #[test]
+    fn metrics() {
+        let metrics = CrawlMetrics::new().unwrap();
+        metrics.observe_request("/videos", "200", 0.3);
+        metrics.videos_parsed.inc_by(2);
+        let text = metrics.render().unwrap();
+        assert!(
+            text.contains("peertube_crawler_requests_total{endpoint=\"/videos\",status=\"200\"} 1")
+        );
+        assert!(text.contains("peertube_crawler_videos_parsed_total 2"));
+    }
+}
@@ -0,0 +1,260 @@
+/// This module finds videos re-uploaded on several instances under different uuids
+use crate::html::render_report;
+use crate::peertube_api::Video;
+use handlebars::RenderError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+/** Maximum difference between the durations of two mirrors, in seconds */
+const DURATION_TOLERANCE: i64 = 2;
+
+/** Minimum similarity for two videos to be considered mirrors */
+pub const MIRROR_THRESHOLD: f64 = 0.8;
+
+/// Lowercases a title and strips punctuation, so that `My Video (HD).mp4` and `my video hd mp4`
+/// compare equal
+pub fn normalize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_lowercase().next().unwrap_or(c)
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn words(text: &str) -> HashSet<&str> {
+    text.split_whitespace().collect()
+}
+
+/// Jaccard index of the words of two texts
+fn jaccard(a: &str, b: &str) -> f64 {
+    let (a, b) = (words(a), words(b));
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f64 / a.union(&b).count() as f64
+}
+
+/// The file name of a thumbnail, which is kept by instances importing a video from another one
+fn thumbnail_name(video: &Video) -> Option<&str> {
+    video
+        .thumbnail_path
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+}
+
+/// Similarity between 0 and 1 of two videos, from their names, durations, descriptions and
+/// thumbnails
+pub fn similarity(a: &Video, b: &Video) -> f64 {
+    let duration_gap = (a.duration - b.duration).abs();
+    if duration_gap > DURATION_TOLERANCE {
+        return 0.0;
+    }
+    let name = jaccard(&normalize_name(&a.name), &normalize_name(&b.name));
+    let duration = 1.0 - duration_gap as f64 / (DURATION_TOLERANCE + 1) as f64;
+    let description = match (&a.description, &b.description) {
+        (Some(x), Some(y)) => jaccard(&normalize_name(x), &normalize_name(y)),
+        (None, None) => 1.0,
+        _ => 0.5,
+    };
+    let thumbnail = match (thumbnail_name(a), thumbnail_name(b)) {
+        (Some(x), Some(y)) if x == y => 1.0,
+        _ => 0.0,
+    };
+    0.55 * name + 0.2 * duration + 0.15 * description + 0.1 * thumbnail
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VideoRef {
+    pub uuid: String,
+    pub host: String,
+    pub name: String,
+    pub url: String,
+    #[serde(rename = "publishedAt")]
+    pub published_at: String,
+    pub views: i64,
+}
+
+impl VideoRef {
+    pub fn new(video: &Video) -> VideoRef {
+        VideoRef {
+            uuid: video.uuid.clone(),
+            host: video.account.host.clone(),
+            name: video.name.clone(),
+            url: format!("https://{}/videos/watch/{}", video.account.host, video.uuid),
+            published_at: video.published_at.clone(),
+            views: video.views,
+        }
+    }
+}
+
+/// A set of likely copies of the same video. The canonical video is the earliest published one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MirrorCluster {
+    pub canonical: VideoRef,
+    pub mirrors: Vec<VideoRef>,
+    /// Lowest similarity between the canonical video and one of its mirrors
+    pub similarity: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MirrorReport {
+    pub videos: usize,
+    pub clusters: Vec<MirrorCluster>,
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    let mut node = i;
+    while parents[node] != root {
+        let next = parents[node];
+        parents[node] = root;
+        node = next;
+    }
+    root
+}
+
+/// Clusters likely mirrors. Videos sharing a uuid are federated copies, not mirrors, and only
+/// the first one is considered. Mirrors are on different instances : videos of the same instance
+/// are never paired, and clusters span at least two instances.
+pub fn find_mirrors(videos: &[Video]) -> MirrorReport {
+    let mut seen = HashSet::new();
+    let videos: Vec<&Video> = videos
+        .iter()
+        .filter(|v| seen.insert(v.uuid.as_str()))
+        .collect();
+
+    // Only videos of (almost) the same duration are compared
+    let mut by_duration: HashMap<i64, Vec<usize>> = HashMap::new();
+    for (i, video) in videos.iter().enumerate() {
+        by_duration.entry(video.duration).or_default().push(i);
+    }
+    let mut parents: Vec<usize> = (0..videos.len()).collect();
+    for (i, video) in videos.iter().enumerate() {
+        for duration in video.duration..=video.duration + DURATION_TOLERANCE {
+            if let Some(candidates) = by_duration.get(&duration) {
+                for &j in candidates
+                    .iter()
+                    .filter(|&&j| j > i || duration != video.duration)
+                {
+                    if video.account.host != videos[j].account.host
+                        && similarity(video, videos[j]) >= MIRROR_THRESHOLD
+                    {
+                        let (a, b) = (find(&mut parents, i), find(&mut parents, j));
+                        parents[a] = b;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<&Video>> = HashMap::new();
+    for (i, video) in videos.iter().enumerate() {
+        let root = find(&mut parents, i);
+        groups.entry(root).or_default().push(video);
+    }
+    let mut clusters: Vec<MirrorCluster> = groups
+        .into_iter()
+        .filter(|(_, group)| {
+            group
+                .iter()
+                .any(|v| v.account.host != group[0].account.host)
+        })
+        .map(|(_, mut group)| {
+            group.sort_by(|a, b| a.published_at.cmp(&b.published_at));
+            let canonical = group[0];
+            MirrorCluster {
+                canonical: VideoRef::new(canonical),
+                mirrors: group[1..].iter().map(|v| VideoRef::new(v)).collect(),
+                similarity: group[1..]
+                    .iter()
+                    .map(|v| similarity(canonical, v))
+                    .fold(1.0, f64::min),
+            }
+        })
+        .collect();
+    clusters.sort_by_key(|c| std::cmp::Reverse(c.mirrors.len()));
+    MirrorReport {
+        videos: videos.len(),
+        clusters,
+    }
+}
+
+/// A cluster as shown in the HTML report
+#[derive(Serialize)]
+struct ClusterView<'a> {
+    name: &'a str,
+    similarity: String,
+    videos: Vec<&'a VideoRef>,
+}
+
+impl MirrorReport {
+    pub fn to_html(&self) -> Result<String, RenderError> {
+        let clusters: Vec<ClusterView> = self
+            .clusters
+            .iter()
+            .map(|cluster| ClusterView {
+                name: &cluster.canonical.name,
+                similarity: format!("{:.2}", cluster.similarity),
+                videos: std::iter::once(&cluster.canonical)
+                    .chain(cluster.mirrors.iter())
+                    .collect(),
+            })
+            .collect();
+        render_report(
+            include_str!("../templates/reports/mirrors.html.hbs"),
+            &json!({ "count": clusters.len(), "videos": self.videos, "clusters": clusters }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mirrors::{find_mirrors, normalize_name, similarity};
+    use crate::peertube_api::Video;
+
+    #[test]
+    fn mirrors() {
+        assert_eq!(normalize_name("My Video (HD).mp4"), "my video hd mp4");
+
+        let json = include_str!("../tests/video1.json");
+        let video: Video = serde_json::from_str(json).unwrap();
+        let mut mirror = video.clone();
+        mirror.uuid = "mirror".to_string();
+        mirror.account.host = "mirror.example".to_string();
+        mirror.name = mirror.name.to_uppercase();
+        mirror.duration += 1;
+        let mut other = video.clone();
+        other.uuid = "other".to_string();
+        other.duration += 60;
+
+        let mut renamed = mirror.clone();
+        renamed.thumbnail_path = "/static/thumbnails/other.jpg".to_string();
+        assert!(similarity(&video, &renamed) < similarity(&video, &mirror));
+        let mut same_host = video.clone();
+        same_host.uuid = "same_host".to_string();
+
+        assert!(find_mirrors(&[video.clone(), same_host.clone()])
+            .clusters
+            .is_empty());
+
+        let report = find_mirrors(&[video.clone(), mirror, other, same_host, video]);
+        assert_eq!(report.videos, 4);
+        assert_eq!(report.clusters.len(), 1);
+        assert_eq!(report.clusters[0].mirrors.len(), 2);
+        let html = report.to_html().unwrap();
+        assert!(html.contains("1 clusters of mirrors among 4 videos"));
+        assert!(html.contains("https://mirror.example/videos/watch/mirror"));
+    }
+}
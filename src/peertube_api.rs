@@ -1,9 +1,4 @@
-use core::fmt;
-use isahc::prelude::*;
-use isahc::ResponseExt;
-use log::info;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
 
 #[derive(Serialize, Deserialize, Debug, Hash, Clone)]
 pub struct Avatar {
@@ -73,6 +68,13 @@ pub struct Video {
     pub account: Account,
     pub channel: Channel,
     pub name: String,
+    /// Only returned when fetching a single video, and read from ActivityPub objects : empty for
+    /// the videos listed by `/videos`, which are searched by name and description only
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Not part of the Peertube API : hides the video from default search results
+    #[serde(default)]
+    pub hidden: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Hash, Clone)]
@@ -113,48 +115,89 @@ peertube_field!(Privacy, i64);
 peertube_field!(Licence, i64);
 peertube_field!(State, i64);
 
-#[derive(Debug)]
-struct JoinPeertubeError(&'static str);
-
-impl fmt::Display for JoinPeertubeError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+/// Reads a page of the `/server/blocklist/servers` endpoint, returning the blocked hosts and the
+/// total number of blocked servers, or None if the page is invalid
+pub fn parse_blocklist_page(json: &serde_json::Value) -> Option<(Vec<String>, u64)> {
+    let hosts = json["data"]
+        .as_array()?
+        .iter()
+        .filter_map(|entry| entry["blockedServer"]["host"].as_str())
+        .map(String::from)
+        .collect();
+    Some((hosts, json["total"].as_u64().unwrap_or(0)))
 }
 
-impl Error for JoinPeertubeError {}
+/// Reads a page of the `/videos` endpoint listing federated videos, returning the hosts of their
+/// accounts and channels other than `local`, the number of videos in the page and the total
+/// number of videos, or None if the page is invalid or empty
+pub fn parse_remote_hosts(
+    json: &serde_json::Value,
+    local: &str,
+) -> Option<(Vec<String>, u64, u64)> {
+    let data = json["data"].as_array().filter(|data| !data.is_empty())?;
+    let hosts = data
+        .iter()
+        .flat_map(|video| vec![&video["account"]["host"], &video["channel"]["host"]])
+        .filter_map(|host| host.as_str())
+        .filter(|host| *host != local)
+        .map(String::from)
+        .collect();
+    Some((
+        hosts,
+        data.len() as u64,
+        json["total"].as_u64().unwrap_or(0),
+    ))
+}
 
-pub fn fetch_instance_list_from_joinpeertube() -> Result<Vec<String>, Box<dyn Error>> {
-    let json =
-        Request::get("https://instances.joinpeertube.org/api/v1/instances?start=0&count=100000000")
-            .body(())?
-            .send()?
-            .json::<serde_json::Value>()?;
-    if let Some(data) = json["data"].as_array() {
-        let mut result = vec![];
-        for value in data {
-            let mut host = value["host"].to_string();
-            host.pop();
-            host.remove(0);
-            result.push(host);
-        }
-        info!("Added {} instances to the instance queue", result.len());
-        Ok(result)
-    } else {
-        Err(Box::new(JoinPeertubeError(
-            "https://instances.joinpeertube.org replied with invalid json",
-        )))
-    }
+/// Reads the version of PeerTube an instance runs from its `/config` endpoint
+pub fn parse_server_version(json: &serde_json::Value) -> Option<String> {
+    json["serverVersion"].as_str().map(String::from)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::peertube_api::Video;
+    use crate::peertube_api::{
+        parse_blocklist_page, parse_remote_hosts, parse_server_version, Video,
+    };
+    use serde_json::json;
 
     #[test]
     fn peertube_api() {
         let json = include_str!("../tests/video1.json");
         let video: Video = serde_json::from_str(json).unwrap();
         println!("{:?}", video);
+
+        let page = json!({
+            "total": 2,
+            "data": [{ "blockedServer": { "host": "spam.example" } }]
+        });
+        assert_eq!(
+            parse_blocklist_page(&page),
+            Some((vec!["spam.example".to_string()], 2))
+        );
+        assert_eq!(parse_blocklist_page(&json!({ "error": "forbidden" })), None);
+        let page = json!({
+            "total": 120,
+            "data": [
+                { "account": { "host": "remote.example" }, "channel": { "host": "local.example" } },
+                { "account": { "host": "local.example" }, "channel": { "host": "other.example" } }
+            ]
+        });
+        assert_eq!(
+            parse_remote_hosts(&page, "local.example"),
+            Some((
+                vec!["remote.example".to_string(), "other.example".to_string()],
+                2,
+                120
+            ))
+        );
+        assert_eq!(
+            parse_remote_hosts(&json!({ "total": 0, "data": [] }), "local.example"),
+            None
+        );
+        assert_eq!(
+            parse_server_version(&json!({ "serverVersion": "6.0.2" })),
+            Some("6.0.2".to_string())
+        );
     }
 }
@@ -0,0 +1,223 @@
+/// This module reports the progress of a crawl, either as terminal progress bars, or as periodic
+/// log lines or JSON events for non-interactive runs
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::info;
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/** Delay between two progress reports in the log and json modes, in seconds */
+const REPORT_INTERVAL: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// Interactive progress bars
+    Bars,
+    /// Periodic log lines
+    Log,
+    /// Periodic JSON events on stderr
+    Json,
+    None,
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bars" => Ok(ProgressMode::Bars),
+            "log" => Ok(ProgressMode::Log),
+            "json" => Ok(ProgressMode::Json),
+            "none" => Ok(ProgressMode::None),
+            _ => Err(format!(
+                "Unknown progress mode {}, expected bars, log, json or none",
+                s
+            )),
+        }
+    }
+}
+
+/// Number of items fetched out of the number known so far, drawn as a bar in the bars mode
+#[derive(Clone)]
+pub struct Counter {
+    bar: ProgressBar,
+    position: Arc<AtomicU64>,
+    length: Arc<AtomicU64>,
+}
+
+impl Counter {
+    fn new(bar: ProgressBar) -> Counter {
+        Counter {
+            bar,
+            position: Arc::new(AtomicU64::new(0)),
+            length: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn tick(&self) {
+        self.bar.tick();
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.position.fetch_add(delta, Ordering::SeqCst);
+        self.bar.inc(delta);
+    }
+
+    pub fn inc_length(&self, delta: u64) {
+        self.length.fetch_add(delta, Ordering::SeqCst);
+        self.bar.inc_length(delta);
+    }
+
+    pub fn set_length(&self, length: u64) {
+        self.length.store(length, Ordering::SeqCst);
+        self.bar.set_length(length);
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position.load(Ordering::SeqCst)
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CounterEvent {
+    pub done: u64,
+    pub total: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProgressEvent {
+    pub event: &'static str,
+    pub elapsed_secs: u64,
+    pub instances: CounterEvent,
+    pub videos: CounterEvent,
+}
+
+#[derive(Clone)]
+pub struct Progress {
+    mode: ProgressMode,
+    multi: Arc<MultiProgress>,
+    pub instances: Counter,
+    pub videos: Counter,
+    start: Instant,
+    finished: Arc<AtomicBool>,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Progress {
+        let multi = Arc::new(MultiProgress::new());
+        let (instance_bar, video_bar) = if mode == ProgressMode::Bars {
+            let instance_bar = multi.add(ProgressBar::new(0));
+            let video_bar = multi.add(ProgressBar::new(0));
+            video_bar.set_prefix("Fetching videos :");
+            instance_bar.set_prefix("Fetching neighbours :");
+            let sty = ProgressStyle::default_bar()
+                .template("{prefix} [{wide_bar:.cyan/blue}] {per_sec} {pos:>7}/{len:7}({percent}%) {eta} remaining")
+                .progress_chars("=>-");
+            instance_bar.set_style(sty.clone());
+            video_bar.set_style(sty);
+            video_bar.tick();
+            instance_bar.tick();
+            (instance_bar, video_bar)
+        } else {
+            (ProgressBar::hidden(), ProgressBar::hidden())
+        };
+        Progress {
+            mode,
+            multi,
+            instances: Counter::new(instance_bar),
+            videos: Counter::new(video_bar),
+            start: Instant::now(),
+            finished: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn event(&self, event: &'static str) -> ProgressEvent {
+        ProgressEvent {
+            event,
+            elapsed_secs: self.start.elapsed().as_secs(),
+            instances: CounterEvent {
+                done: self.instances.position(),
+                total: self.instances.length(),
+            },
+            videos: CounterEvent {
+                done: self.videos.position(),
+                total: self.videos.length(),
+            },
+        }
+    }
+
+    fn report(&self, event: &'static str) {
+        let event = self.event(event);
+        match self.mode {
+            ProgressMode::Log => info!(
+                "Progress : {}/{} instances, {}/{} videos, {} seconds elapsed",
+                event.instances.done,
+                event.instances.total,
+                event.videos.done,
+                event.videos.total,
+                event.elapsed_secs
+            ),
+            ProgressMode::Json => {
+                eprintln!("{}", serde_json::to_string(&event).unwrap_or_default())
+            }
+            ProgressMode::Bars | ProgressMode::None => (),
+        }
+    }
+
+    /// Draws the bars, or reports the progress periodically, from a background thread
+    pub fn start(&self) {
+        match self.mode {
+            ProgressMode::Bars => {
+                let multi = self.multi.clone();
+                thread::spawn(move || multi.join().unwrap());
+            }
+            ProgressMode::Log | ProgressMode::Json => {
+                let progress = self.clone();
+                thread::spawn(move || loop {
+                    for _ in 0..REPORT_INTERVAL * 10 {
+                        thread::sleep(Duration::from_millis(100));
+                        if progress.finished.load(Ordering::SeqCst) {
+                            return;
+                        }
+                    }
+                    progress.report("progress");
+                });
+            }
+            ProgressMode::None => (),
+        }
+    }
+
+    pub fn finish(&self, instances_message: &str, videos_message: &str) {
+        self.finished.store(true, Ordering::SeqCst);
+        self.instances.bar.finish_with_message(instances_message);
+        self.videos.bar.finish_with_message(videos_message);
+        self.report("finished");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::progress::{Progress, ProgressMode};
+
+    #[test]
+    fn progress() {
+        let progress = Progress::new(ProgressMode::None);
+        progress.instances.set_length(3);
+        progress.instances.inc_length(2);
+        progress.instances.inc(1);
+        progress.videos.inc_length(10);
+        progress.videos.inc(4);
+        let event = serde_json::to_value(progress.event("progress")).unwrap();
+        assert_eq!(event["instances"]["done"], 1);
+        assert_eq!(event["instances"]["total"], 5);
+        assert_eq!(event["videos"]["done"], 4);
+        assert_eq!(event["videos"]["total"], 10);
+    }
+}
@@ -0,0 +1,302 @@
+/// This module ranks search results by combining the text relevance with the popularity and
+/// freshness of the videos and the reputation of their instance. The weights are read from a JSON
+/// file, so that the ranking can be tuned without recompiling.
+use crate::mirrors::{similarity, MIRROR_THRESHOLD};
+use crate::peertube_api::Video;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/** Instances are grouped by the order of magnitude of their number of videos */
+const SIZE_TIERS: &[u64] = &[10, 100, 1000, 10000];
+
+/// Weights of the ranking factors. A weight of 0 disables a factor.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RankingWeights {
+    /// Weight of the BM25 text relevance, added to the other factors
+    pub text: f64,
+    /// Weight of the logarithm of the number of views
+    pub views: f64,
+    /// Weight of the smoothed ratio of likes among likes and dislikes
+    pub likes: f64,
+    /// Weight of the freshness, decaying by half every `recency_scale_days`
+    pub recency: f64,
+    pub recency_scale_days: u64,
+    /// Factor applied to the videos of instances unreachable on their last crawl
+    pub unhealthy_penalty: f64,
+    /// Weight of each order of magnitude of the number of videos of the instance
+    pub instance_size: f64,
+    /// Factor applied to the videos mirroring a better ranked result
+    pub duplicate_penalty: f64,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        RankingWeights {
+            text: 1.0,
+            views: 0.5,
+            likes: 0.5,
+            recency: 0.5,
+            recency_scale_days: 180,
+            unhealthy_penalty: 0.5,
+            instance_size: 0.1,
+            duplicate_penalty: 0.3,
+        }
+    }
+}
+
+impl RankingWeights {
+    pub fn load(path: &Path) -> Result<RankingWeights, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Weights read from a file, reloaded when the file changes. The default weights are used when
+/// the file is missing or invalid.
+pub struct WeightsFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    weights: RankingWeights,
+}
+
+impl WeightsFile {
+    pub fn new(path: PathBuf) -> WeightsFile {
+        let mut file = WeightsFile {
+            path,
+            modified: None,
+            weights: RankingWeights::default(),
+        };
+        file.reload();
+        file
+    }
+
+    /// Returns the current weights, reloading the file if it was modified
+    pub fn weights(&mut self) -> &RankingWeights {
+        self.reload();
+        &self.weights
+    }
+
+    fn reload(&mut self) {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        self.weights = match modified {
+            Some(_) => match RankingWeights::load(&self.path) {
+                Ok(weights) => weights,
+                Err(e) => {
+                    warn!("Invalid ranking weights in {:?} : {}", self.path, e);
+                    RankingWeights::default()
+                }
+            },
+            None => RankingWeights::default(),
+        };
+    }
+}
+
+/// Health and size of an instance, from its last crawl
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstanceReputation {
+    pub host: String,
+    pub healthy: bool,
+    pub videos: u64,
+}
+
+/// The weights and reputations used to rank a search
+#[derive(Debug, Clone, Default)]
+pub struct Ranking {
+    pub weights: RankingWeights,
+    pub reputations: Vec<InstanceReputation>,
+}
+
+impl Ranking {
+    /// Wraps a text query into a function_score query. The factors are summed and added to the
+    /// weighted text relevance, and the instance penalties are applied on top.
+    pub fn function_score(&self, query: serde_json::Value) -> serde_json::Value {
+        let w = &self.weights;
+        let mut factors = vec![];
+        if w.views > 0.0 {
+            factors.push(json!({
+                "field_value_factor": { "field": "views", "modifier": "log1p", "missing": 0 },
+                "weight": w.views
+            }));
+        }
+        if w.likes > 0.0 {
+            factors.push(json!({
+                "script_score": { "script": {
+                    "source": "(doc['likes'].value + 1.0) \
+                               / (doc['likes'].value + doc['dislikes'].value + 2.0)"
+                }},
+                "weight": w.likes
+            }));
+        }
+        if w.recency > 0.0 {
+            factors.push(json!({
+                "gauss": { "publishedAt": {
+                    "origin": "now",
+                    "scale": format!("{}d", w.recency_scale_days.max(1)),
+                    "decay": 0.5
+                }},
+                "weight": w.recency
+            }));
+        }
+        if w.instance_size > 0.0 {
+            for (tier, min) in SIZE_TIERS.iter().enumerate() {
+                let hosts = self.hosts(|r| r.videos >= *min);
+                if !hosts.is_empty() {
+                    factors.push(json!({
+                        "filter": { "terms": { "account.host.keyword": hosts } },
+                        "weight": w.instance_size * (tier + 1) as f64
+                    }));
+                }
+            }
+        }
+        let mut ranked = json!({
+            "function_score": {
+                "query": { "bool": { "must": query, "boost": w.text.max(0.0) } },
+                "functions": factors,
+                "score_mode": "sum",
+                "boost_mode": "sum"
+            }
+        });
+        let unhealthy = self.hosts(|r| !r.healthy);
+        if w.unhealthy_penalty != 1.0 && !unhealthy.is_empty() {
+            ranked = json!({
+                "function_score": {
+                    "query": ranked,
+                    "functions": [{
+                        "filter": { "terms": { "account.host.keyword": unhealthy } },
+                        "weight": w.unhealthy_penalty.max(0.0)
+                    }],
+                    "boost_mode": "multiply"
+                }
+            });
+        }
+        ranked
+    }
+
+    fn hosts<F: Fn(&InstanceReputation) -> bool>(&self, predicate: F) -> Vec<&str> {
+        self.reputations
+            .iter()
+            .filter(|r| predicate(r))
+            .map(|r| r.host.as_str())
+            .collect()
+    }
+
+    /// Demotes the results mirroring a better ranked one, and sorts the results by score. Only
+    /// the results of the same page are compared, so a mirror of a result of a previous page is
+    /// not demoted.
+    pub fn penalize_duplicates(&self, mut hits: Vec<(f64, Video)>) -> Vec<Video> {
+        hits.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        for i in 1..hits.len() {
+            let (better, rest) = hits.split_at_mut(i);
+            if better
+                .iter()
+                .any(|(_, video)| similarity(video, &rest[0].1) >= MIRROR_THRESHOLD)
+            {
+                rest[0].0 *= self.weights.duplicate_penalty;
+            }
+        }
+        hits.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        hits.into_iter().map(|(_, video)| video).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peertube_api::Video;
+    use crate::ranking::{InstanceReputation, Ranking, RankingWeights};
+    use serde_json::json;
+
+    #[test]
+    fn ranking() {
+        let weights: RankingWeights = serde_json::from_str(r#"{ "views": 2.0 }"#).unwrap();
+        assert_eq!(weights.views, 2.0);
+        assert_eq!(weights.likes, RankingWeights::default().likes);
+
+        let ranking = Ranking {
+            weights,
+            reputations: vec![
+                InstanceReputation {
+                    host: "big.example".to_string(),
+                    healthy: true,
+                    videos: 5000,
+                },
+                InstanceReputation {
+                    host: "down.example".to_string(),
+                    healthy: false,
+                    videos: 5,
+                },
+            ],
+        };
+        let query = ranking.function_score(json!({ "match_all": {} }));
+        let outer = &query["function_score"];
+        assert_eq!(outer["boost_mode"], "multiply");
+        assert_eq!(
+            outer["functions"][0]["filter"]["terms"]["account.host.keyword"],
+            json!(["down.example"])
+        );
+        assert_eq!(outer["functions"][0]["weight"], 0.5);
+
+        // The text relevance is added to the sum of the other factors
+        let inner = &outer["query"]["function_score"];
+        assert_eq!(inner["query"]["bool"]["must"], json!({ "match_all": {} }));
+        assert_eq!(inner["query"]["bool"]["boost"], 1.0);
+        assert_eq!(inner["score_mode"], "sum");
+        assert_eq!(inner["boost_mode"], "sum");
+        let functions = inner["functions"].as_array().unwrap();
+        // The views, likes, recency and three size tiers
+        assert_eq!(functions.len(), 6);
+        assert_eq!(functions[0]["field_value_factor"]["field"], "views");
+        assert_eq!(functions[0]["weight"], 2.0);
+        assert!(functions[1]["script_score"].is_object());
+        assert_eq!(functions[1]["weight"], 0.5);
+        assert_eq!(functions[2]["gauss"]["publishedAt"]["scale"], "180d");
+        assert_eq!(functions[2]["weight"], 0.5);
+        let tiers: Vec<f64> = functions[3..]
+            .iter()
+            .map(|f| f["weight"].as_f64().unwrap())
+            .collect();
+        assert!(tiers.windows(2).all(|w| w[0] < w[1]));
+        assert!((tiers[0] - 0.1).abs() < 1e-9);
+        assert_eq!(
+            functions[5]["filter"]["terms"]["account.host.keyword"],
+            json!(["big.example"])
+        );
+
+        // Disabled factors are left out, and without a penalty there is no outer query
+        let query = Ranking {
+            weights: RankingWeights {
+                text: 0.2,
+                likes: 0.0,
+                unhealthy_penalty: 1.0,
+                ..RankingWeights::default()
+            },
+            reputations: ranking.reputations.clone(),
+        }
+        .function_score(json!({ "match_all": {} }));
+        let function_score = &query["function_score"];
+        assert_eq!(function_score["query"]["bool"]["boost"], 0.2);
+        assert_eq!(function_score["boost_mode"], "sum");
+        assert_eq!(function_score["functions"].as_array().unwrap().len(), 5);
+
+        let video: Video = serde_json::from_str(include_str!("../tests/video1.json")).unwrap();
+        let mut mirror = video.clone();
+        mirror.uuid = "mirror".to_string();
+        let mut other = video.clone();
+        other.uuid = "other".to_string();
+        other.name = "Something else entirely".to_string();
+        other.duration += 100;
+        let ranked = ranking.penalize_duplicates(vec![(10.0, video), (9.0, mirror), (5.0, other)]);
+        let uuids: Vec<&str> = ranked.iter().map(|v| v.uuid.as_str()).collect();
+        assert_eq!(uuids[1..], ["other", "mirror"]);
+    }
+}
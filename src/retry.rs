@@ -0,0 +1,129 @@
+/// This module decides whether a failed request is worth retrying, and how long to wait before
+use chrono::{DateTime, Utc};
+use isahc::http::StatusCode;
+use rand::Rng;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// May succeed later : timeouts, overloaded or rate limiting servers
+    Transient,
+    /// Will fail again : unknown hosts, refused connections, invalid certificates, missing
+    /// endpoints
+    Permanent,
+}
+
+pub fn classify_error(e: &isahc::Error) -> ErrorClass {
+    match e {
+        isahc::Error::CouldntResolveHost
+        | isahc::Error::ConnectFailed
+        | isahc::Error::BadServerCertificate(_)
+        | isahc::Error::SSLConnectFailed(_)
+        | isahc::Error::SSLEngineError(_)
+        | isahc::Error::InvalidContentEncoding(_)
+        | isahc::Error::TooManyRedirects => ErrorClass::Permanent,
+        _ => ErrorClass::Transient,
+    }
+}
+
+/// Classifies an HTTP status, `None` meaning success
+pub fn classify_status(status: StatusCode) -> Option<ErrorClass> {
+    match status.as_u16() {
+        200..=299 => None,
+        408 | 425 | 429 | 500 | 502 | 503 | 504 => Some(ErrorClass::Transient),
+        _ => Some(ErrorClass::Permanent),
+    }
+}
+
+/// Parses a `Retry-After` header, given either in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    (date.with_timezone(&Utc) - now).to_std().ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the given retry (starting at 0) : the server's `Retry-After` if any, else an
+    /// exponential backoff with full jitter
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(delay) = retry_after {
+            return delay.min(self.max_delay);
+        }
+        let backoff = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(retry))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::retry::{
+        classify_error, classify_status, parse_retry_after, ErrorClass, RetryPolicy,
+    };
+    use chrono::{DateTime, Utc};
+    use isahc::http::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn retry() {
+        assert_eq!(
+            classify_error(&isahc::Error::ConnectFailed),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            classify_error(&isahc::Error::Timeout),
+            ErrorClass::Transient
+        );
+        assert_eq!(classify_status(StatusCode::OK), None);
+        assert_eq!(
+            classify_status(StatusCode::TOO_MANY_REQUESTS),
+            Some(ErrorClass::Transient)
+        );
+        assert_eq!(
+            classify_status(StatusCode::NOT_FOUND),
+            Some(ErrorClass::Permanent)
+        );
+
+        let now = DateTime::parse_from_rfc3339("2020-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 01 Mar 2020 12:00:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+
+        let policy = RetryPolicy::default();
+        assert!(policy.delay(10, None) <= policy.max_delay);
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+    }
+}
@@ -0,0 +1,73 @@
+/// This module decides when an instance is crawled again, from its observed activity
+use chrono::{DateTime, Duration, Utc};
+
+/** Instances which published a video within this delay are busy */
+const BUSY_WITHIN_HOURS: i64 = 24;
+
+/** Instances which published a video within this delay are active, the others are dormant */
+const ACTIVE_WITHIN_DAYS: i64 = 30;
+
+/** Delay before resuming the crawl of an instance which failed midway, in hours */
+pub const PARTIAL_RECRAWL_HOURS: i64 = 1;
+
+/** Delay between two fetches of the seed sources, in hours */
+pub const RESEED_INTERVAL_HOURS: i64 = 24;
+
+/** Delay before fetching the seed sources again after they all failed, in hours */
+pub const RESEED_RETRY_HOURS: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activity {
+    /// Recrawled hourly
+    Busy,
+    /// Recrawled daily
+    Active,
+    /// Recrawled weekly, including unreachable instances
+    Dormant,
+}
+
+impl Activity {
+    /// Classifies an instance from the publication date of its latest video
+    pub fn new(latest_published_at: Option<&str>, now: DateTime<Utc>) -> Activity {
+        let latest = match latest_published_at.and_then(|d| DateTime::parse_from_rfc3339(d).ok()) {
+            Some(date) => date.with_timezone(&Utc),
+            None => return Activity::Dormant,
+        };
+        if now - latest < Duration::hours(BUSY_WITHIN_HOURS) {
+            Activity::Busy
+        } else if now - latest < Duration::days(ACTIVE_WITHIN_DAYS) {
+            Activity::Active
+        } else {
+            Activity::Dormant
+        }
+    }
+
+    pub fn recrawl_interval(self) -> Duration {
+        match self {
+            Activity::Busy => Duration::hours(1),
+            Activity::Active => Duration::days(1),
+            Activity::Dormant => Duration::weeks(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::schedule::Activity;
+    use chrono::{DateTime, Utc};
+
+    #[test]
+    fn activity() {
+        let now = DateTime::parse_from_rfc3339("2020-03-01T12:00:00.000Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let activity = |date| Activity::new(date, now);
+        assert_eq!(activity(Some("2020-03-01T08:00:00.000Z")), Activity::Busy);
+        assert_eq!(activity(Some("2020-02-20T08:00:00.000Z")), Activity::Active);
+        assert_eq!(
+            activity(Some("2019-06-01T08:00:00.000Z")),
+            Activity::Dormant
+        );
+        assert_eq!(activity(None), Activity::Dormant);
+    }
+}
@@ -0,0 +1,330 @@
+/// This module queries the Elastic database for videos
+use crate::elastic::{Elastic, EsError, INDEX_ALIAS};
+use crate::peertube_api::Video;
+use crate::ranking::Ranking;
+use serde::Serialize;
+use serde_json::json;
+
+/** Fields completed by the suggestions, indexed as `search_as_you_type` */
+const SUGGEST_FIELDS: &[&str] = &[
+    "name.suggest",
+    "channel.displayName.suggest",
+    "tags.suggest",
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestionKind {
+    Video,
+    Channel,
+    Tag,
+}
+
+/// A completion of the text typed in the search box
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub text: String,
+    pub kind: SuggestionKind,
+}
+
+/// A full text search over the indexed videos
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    /// Include the NSFW videos hidden by the crawler policy
+    pub nsfw: bool,
+    pub from: u64,
+    pub size: u64,
+    /// Ranks the results by popularity, freshness and instance reputation, rather than by text
+    /// relevance only
+    pub ranking: Option<Ranking>,
+}
+
+impl SearchQuery {
+    pub fn new(text: String) -> SearchQuery {
+        SearchQuery {
+            text,
+            nsfw: false,
+            from: 0,
+            size: 20,
+            ranking: None,
+        }
+    }
+
+    /// Builds the Elastic Search request body
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut query = json!({
+            "bool": {
+                "must": {
+                    "multi_match": {
+                        "query": self.text,
+                        "fields": [
+                            "name^3",
+                            "description",
+                            "i18n.*.name^3",
+                            "i18n.*.description",
+                            "tags",
+                            "account.displayName",
+                            "channel.displayName"
+                        ]
+                    }
+                }
+            }
+        });
+        if !self.nsfw {
+            query["bool"]["must_not"] = json!({ "term": { "hidden": true } });
+        }
+        if let Some(ranking) = &self.ranking {
+            query = ranking.function_score(query);
+        }
+        json!({
+            "from": self.from,
+            "size": self.size,
+            "query": query
+        })
+    }
+}
+
+/// Runs a search, returning the videos found with their score
+fn scored_hits(elastic: &Elastic, body: &serde_json::Value) -> Result<Vec<(f64, Video)>, EsError> {
+    let json = elastic.post(&format!("/{}/_search", INDEX_ALIAS), body)?;
+    match json["hits"]["hits"].as_array() {
+        Some(hits) => Ok(hits
+            .iter()
+            .filter_map(|hit| {
+                let video = serde_json::from_value(hit["_source"].clone()).ok()?;
+                Some((hit["_score"].as_f64().unwrap_or(0.0), video))
+            })
+            .collect()),
+        None => Err(EsError::InvalidResponse(json.to_string())),
+    }
+}
+
+pub fn search_videos(elastic: &Elastic, query: &SearchQuery) -> Result<Vec<Video>, EsError> {
+    let hits = scored_hits(elastic, &query.to_json())?;
+    Ok(match &query.ranking {
+        Some(ranking) => ranking.penalize_duplicates(hits),
+        None => hits.into_iter().map(|(_, video)| video).collect(),
+    })
+}
+
+/// Builds the Elastic Search request body finding the videos similar to a video by their name,
+/// description and tags
+pub fn related_json(uuid: &str, nsfw: bool, size: u64) -> serde_json::Value {
+    let mut must_not = vec![json!({ "ids": { "values": [uuid] } })];
+    if !nsfw {
+        must_not.push(json!({ "term": { "hidden": true } }));
+    }
+    json!({
+        "size": size,
+        "query": {
+            "bool": {
+                "must": {
+                    "more_like_this": {
+                        "fields": ["name", "description", "tags"],
+                        "like": [{ "_index": INDEX_ALIAS, "_id": uuid }],
+                        "min_term_freq": 1,
+                        "min_doc_freq": 2
+                    }
+                },
+                "must_not": must_not
+            }
+        }
+    })
+}
+
+/// Builds the Elastic Search request body listing the other videos of the channel of a video,
+/// newest first
+pub fn channel_json(video: &Video, nsfw: bool, size: u64) -> serde_json::Value {
+    let mut must_not = vec![json!({ "ids": { "values": [video.uuid] } })];
+    if !nsfw {
+        must_not.push(json!({ "term": { "hidden": true } }));
+    }
+    json!({
+        "size": size,
+        "sort": [{ "publishedAt": "desc" }],
+        "query": {
+            "bool": {
+                "filter": [
+                    { "term": { "channel.name.keyword": video.channel.name } },
+                    { "term": { "channel.host.keyword": video.channel.host } }
+                ],
+                "must_not": must_not
+            }
+        }
+    })
+}
+
+pub fn related_videos(
+    elastic: &Elastic,
+    uuid: &str,
+    nsfw: bool,
+    size: u64,
+) -> Result<Vec<Video>, EsError> {
+    let hits = scored_hits(elastic, &related_json(uuid, nsfw, size))?;
+    Ok(hits.into_iter().map(|(_, video)| video).collect())
+}
+
+pub fn channel_videos(
+    elastic: &Elastic,
+    video: &Video,
+    nsfw: bool,
+    size: u64,
+) -> Result<Vec<Video>, EsError> {
+    let hits = scored_hits(elastic, &channel_json(video, nsfw, size))?;
+    Ok(hits.into_iter().map(|(_, video)| video).collect())
+}
+
+/// Whether every word of the typed text starts a word of the candidate, ignoring case
+pub fn completes(candidate: &str, text: &str) -> bool {
+    let words: Vec<String> = candidate
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .collect();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_lowercase)
+        .all(|prefix| words.iter().any(|word| word.starts_with(&prefix)))
+}
+
+/// Picks the names, channels and tags of the matching videos completing the typed text, without
+/// duplicates, in the order of the videos
+pub fn collect_suggestions<'a, I: IntoIterator<Item = &'a Video>>(
+    videos: I,
+    text: &str,
+    size: usize,
+) -> Vec<Suggestion> {
+    let mut suggestions: Vec<Suggestion> = vec![];
+    for video in videos {
+        let candidates = std::iter::once((&video.name, SuggestionKind::Video))
+            .chain(std::iter::once((
+                &video.channel.display_name,
+                SuggestionKind::Channel,
+            )))
+            .chain(video.tags.iter().map(|tag| (tag, SuggestionKind::Tag)));
+        for (candidate, kind) in candidates {
+            if suggestions.len() == size {
+                return suggestions;
+            }
+            if completes(candidate, text)
+                && !suggestions
+                    .iter()
+                    .any(|s| s.text.to_lowercase() == candidate.to_lowercase())
+            {
+                suggestions.push(Suggestion {
+                    text: candidate.clone(),
+                    kind,
+                });
+            }
+        }
+    }
+    suggestions
+}
+
+/// Builds the Elastic Search request body matching the videos completing the typed text
+pub fn suggest_json(text: &str, nsfw: bool, size: u64) -> serde_json::Value {
+    let fields: Vec<String> = SUGGEST_FIELDS
+        .iter()
+        .flat_map(|field| {
+            vec![
+                field.to_string(),
+                format!("{}._2gram", field),
+                format!("{}._3gram", field),
+            ]
+        })
+        .collect();
+    let mut query = json!({
+        "bool": {
+            "must": {
+                "multi_match": {
+                    "query": text,
+                    "type": "bool_prefix",
+                    "fields": fields
+                }
+            }
+        }
+    });
+    if !nsfw {
+        query["bool"]["must_not"] = json!({ "term": { "hidden": true } });
+    }
+    json!({
+        "size": size,
+        "query": query
+    })
+}
+
+/// Suggests completions of the typed text, looking at a few more videos than suggestions since
+/// videos often share their channel
+pub fn suggest_videos(
+    elastic: &Elastic,
+    text: &str,
+    nsfw: bool,
+    size: u64,
+) -> Result<Vec<Suggestion>, EsError> {
+    let json = elastic.post(
+        &format!("/{}/_search", INDEX_ALIAS),
+        &suggest_json(text, nsfw, size * 3),
+    )?;
+    match json["hits"]["hits"].as_array() {
+        Some(hits) => {
+            let videos: Vec<Video> = hits
+                .iter()
+                .filter_map(|hit| serde_json::from_value(hit["_source"].clone()).ok())
+                .collect();
+            Ok(collect_suggestions(&videos, text, size as usize))
+        }
+        None => Err(EsError::InvalidResponse(json.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peertube_api::Video;
+    use crate::search::{
+        channel_json, collect_suggestions, completes, related_json, suggest_json, SuggestionKind,
+    };
+
+    #[test]
+    fn suggestions() {
+        assert!(completes("Les Dessous de l'agro-industrie", "dess agro"));
+        assert!(!completes("Les Dessous de l'agro-industrie", "dessin"));
+
+        let mut video: Video = serde_json::from_str(include_str!("../tests/video1.json")).unwrap();
+        video.name = "Peertube tutorial".to_string();
+        video.channel.display_name = "Peertube channel".to_string();
+        video.tags = vec!["peer".to_string(), "tube".to_string()];
+        let mirror = video.clone();
+        let suggestions = collect_suggestions(&[video, mirror], "pee", 10);
+        let texts: Vec<&str> = suggestions.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["Peertube tutorial", "Peertube channel", "peer"]);
+        assert_eq!(suggestions[2].kind, SuggestionKind::Tag);
+
+        let json = suggest_json("pee", false, 5);
+        assert_eq!(
+            json["query"]["bool"]["must"]["multi_match"]["type"],
+            "bool_prefix"
+        );
+        assert_eq!(json["query"]["bool"]["must_not"]["term"]["hidden"], true);
+    }
+
+    #[test]
+    fn related() {
+        let video: Video = serde_json::from_str(include_str!("../tests/video1.json")).unwrap();
+        let json = related_json(&video.uuid, true, 6);
+        let query = &json["query"]["bool"];
+        assert_eq!(
+            query["must"]["more_like_this"]["like"][0]["_id"],
+            video.uuid
+        );
+        assert_eq!(query["must_not"][0]["ids"]["values"][0], video.uuid);
+        assert_eq!(query["must_not"].as_array().unwrap().len(), 1);
+
+        let json = channel_json(&video, false, 6);
+        let query = &json["query"]["bool"];
+        assert_eq!(
+            query["filter"][0]["term"]["channel.name.keyword"],
+            "renaudf_channel"
+        );
+        assert_eq!(query["must_not"].as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,261 @@
+/// This module gathers the instances a crawl starts from, from several sources. Each instance
+/// keeps the names of the sources that listed it.
+use futures::Future;
+use isahc::prelude::*;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const JOINPEERTUBE_URL: &str = "https://instances.joinpeertube.org/api/v1/instances";
+
+pub const FEDIVERSE_OBSERVER_URL: &str = "https://api.fediverse.observer/";
+
+/** Number of instances requested at once from instance directories */
+const DIRECTORY_PAGE_SIZE: u64 = 500;
+
+const DIRECTORY_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub type SeedFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<String>, Box<dyn Error>>> + 'a>>;
+
+pub trait SeedSource {
+    /// Name recorded as the provenance of the instances
+    fn name(&self) -> String;
+
+    /// Lists the hosts of the source, possibly as URLs
+    fn fetch<'a>(&'a self, client: &'a HttpClient) -> SeedFuture<'a>;
+}
+
+/// Turns `https://Video.Example/about` into `video.example`
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim();
+    let host = host
+        .strip_prefix("https://")
+        .or_else(|| host.strip_prefix("http://"))
+        .unwrap_or(host);
+    let host = host.split('/').next().unwrap_or_default().to_lowercase();
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+/// The instance list of https://instances.joinpeertube.org
+pub struct JoinPeertube {
+    pub url: String,
+}
+
+impl Default for JoinPeertube {
+    fn default() -> Self {
+        JoinPeertube {
+            url: JOINPEERTUBE_URL.to_string(),
+        }
+    }
+}
+
+impl SeedSource for JoinPeertube {
+    fn name(&self) -> String {
+        "joinpeertube".to_string()
+    }
+
+    fn fetch<'a>(&'a self, client: &'a HttpClient) -> SeedFuture<'a> {
+        Box::pin(async move {
+            let mut hosts = vec![];
+            // Entries without a host are skipped, so the pages are counted apart from the hosts
+            let (mut start, mut total) = (0, 1);
+            while start < total {
+                let request = Request::get(format!(
+                    "{}?start={}&count={}",
+                    self.url, start, DIRECTORY_PAGE_SIZE
+                ))
+                .timeout(DIRECTORY_TIMEOUT)
+                .body(())?;
+                let json = client
+                    .send_async(request)
+                    .await?
+                    .json::<serde_json::Value>()?;
+                let data = match json["data"].as_array() {
+                    Some(data) if !data.is_empty() => data,
+                    Some(_) => break,
+                    None => return Err(format!("{} replied with invalid json", self.url).into()),
+                };
+                total = json["total"].as_u64().unwrap_or(0);
+                start += data.len() as u64;
+                hosts.extend(
+                    data.iter()
+                        .filter_map(|instance| instance["host"].as_str())
+                        .map(String::from),
+                );
+            }
+            Ok(hosts)
+        })
+    }
+}
+
+/// The PeerTube instances known by https://fediverse.observer
+pub struct FediverseObserver {
+    pub url: String,
+}
+
+impl Default for FediverseObserver {
+    fn default() -> Self {
+        FediverseObserver {
+            url: FEDIVERSE_OBSERVER_URL.to_string(),
+        }
+    }
+}
+
+impl SeedSource for FediverseObserver {
+    fn name(&self) -> String {
+        "fediverse.observer".to_string()
+    }
+
+    fn fetch<'a>(&'a self, client: &'a HttpClient) -> SeedFuture<'a> {
+        Box::pin(async move {
+            let query = serde_json::json!({
+                "query": "{nodes(softwarename: \"peertube\") {domain}}"
+            });
+            let request = Request::post(&self.url)
+                .header("Content-Type", "application/json")
+                .timeout(DIRECTORY_TIMEOUT)
+                .body(query.to_string())?;
+            let json = client
+                .send_async(request)
+                .await?
+                .json::<serde_json::Value>()?;
+            match json["data"]["nodes"].as_array() {
+                Some(nodes) => Ok(nodes
+                    .iter()
+                    .filter_map(|node| node["domain"].as_str())
+                    .map(String::from)
+                    .collect()),
+                None => Err(format!("{} replied with invalid json", self.url).into()),
+            }
+        })
+    }
+}
+
+/// A file of hosts, one per line, `#` starting comments
+pub struct HostFile {
+    pub path: PathBuf,
+}
+
+impl SeedSource for HostFile {
+    fn name(&self) -> String {
+        format!("file:{}", self.path.to_string_lossy())
+    }
+
+    fn fetch<'a>(&'a self, _client: &'a HttpClient) -> SeedFuture<'a> {
+        Box::pin(async move {
+            Ok(fs::read_to_string(&self.path)?
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect())
+        })
+    }
+}
+
+/// A fixed list of hosts, such as the instances already known from previous crawls
+pub struct HostList {
+    pub name: String,
+    pub hosts: Vec<String>,
+}
+
+impl SeedSource for HostList {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn fetch<'a>(&'a self, _client: &'a HttpClient) -> SeedFuture<'a> {
+        Box::pin(async move { Ok(self.hosts.clone()) })
+    }
+}
+
+/** Name of the seed source listing the instances already in the database */
+pub const KNOWN_SOURCE: &str = "known";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeedSpec {
+    JoinPeertube,
+    FediverseObserver,
+    /// Instances already in the database
+    Known,
+    File(PathBuf),
+}
+
+impl FromStr for SeedSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "joinpeertube" => Ok(SeedSpec::JoinPeertube),
+            "fediverse-observer" => Ok(SeedSpec::FediverseObserver),
+            KNOWN_SOURCE => Ok(SeedSpec::Known),
+            _ => match s.strip_prefix("file:") {
+                Some(path) => Ok(SeedSpec::File(PathBuf::from(path))),
+                None => Err(format!(
+                    "Unknown seed source {}, expected joinpeertube, fediverse-observer, known or \
+                     file:<path>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Merges the hosts of every source, along with the sources listing them. Failing sources are
+/// skipped.
+pub async fn collect_seeds(
+    sources: &[Box<dyn SeedSource>],
+    client: &HttpClient,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let mut seeds: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for source in sources {
+        match source.fetch(client).await {
+            Ok(hosts) => {
+                for host in hosts.iter().filter_map(|host| normalize_host(host)) {
+                    seeds.entry(host).or_default().insert(source.name());
+                }
+            }
+            Err(e) => warn!("Failed to fetch instances from {} : {}", source.name(), e),
+        }
+    }
+    seeds
+}
+
+#[cfg(test)]
+mod test {
+    use crate::seed_sources::{collect_seeds, normalize_host, HostList, SeedSource};
+    use futures::executor::block_on;
+    use isahc::HttpClient;
+
+    #[test]
+    fn seed_sources() {
+        assert_eq!(
+            normalize_host("https://Video.Example/about"),
+            Some("video.example".to_string())
+        );
+        assert_eq!(normalize_host("  "), None);
+
+        let sources: Vec<Box<dyn SeedSource>> = vec![
+            Box::new(HostList {
+                name: "a".to_string(),
+                hosts: vec!["one.example".to_string(), "TWO.example".to_string()],
+            }),
+            Box::new(HostList {
+                name: "b".to_string(),
+                hosts: vec!["https://two.example/".to_string()],
+            }),
+        ];
+        let seeds = block_on(collect_seeds(&sources, &HttpClient::new().unwrap()));
+        assert_eq!(seeds.len(), 2);
+        assert_eq!(seeds["two.example"].len(), 2);
+    }
+}
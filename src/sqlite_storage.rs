@@ -0,0 +1,312 @@
+/// This module stores videos in SQLite, next to the instances, for deployments without Elastic Search
+use crate::peertube_api::Video;
+use crate::search::{collect_suggestions, SearchQuery, Suggestion};
+use crate::video_storage::{StoredVideo, VideoStorage};
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::error::Error;
+
+pub struct SqliteDatabase {
+    conn: Connection,
+}
+
+impl Default for SqliteDatabase {
+    fn default() -> Self {
+        Self::new("instances.db")
+    }
+}
+
+impl SqliteDatabase {
+    pub fn new(path: &str) -> SqliteDatabase {
+        let conn = Connection::open(path).expect("Failed to open DB");
+        conn.execute(
+            "create table if not exists peertube_videos (
+             uuid text primary key,
+             host text not null,
+             hidden integer not null,
+             document text not null
+         )",
+            NO_PARAMS,
+        )
+        .expect("Failed to create table");
+        // Full text indexes created before the channels were indexed are rebuilt with them
+        let columns = conn
+            .prepare("select name from pragma_table_info('peertube_videos_fts')")
+            .and_then(|mut stmt| {
+                stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+                    .map(|names| names.filter_map(Result::ok).collect::<Vec<String>>())
+            })
+            .expect("Failed to read the full text index");
+        if !columns.is_empty() && !columns.iter().any(|column| column == "channel") {
+            conn.execute("drop table peertube_videos_fts", NO_PARAMS)
+                .expect("Failed to drop the full text index");
+        }
+        conn.execute(
+            "create virtual table if not exists peertube_videos_fts using fts5(
+             uuid unindexed,
+             name,
+             description,
+             tags,
+             channel
+         )",
+            NO_PARAMS,
+        )
+        .expect("Failed to create full text index");
+        if !columns.is_empty() && !columns.iter().any(|column| column == "channel") {
+            conn.execute(
+                "insert into peertube_videos_fts (uuid, name, description, tags, channel)
+                 select uuid, json_extract(document, '$.name'),
+                 json_extract(document, '$.description'),
+                 (select group_concat(value, ' ') from json_each(document, '$.tags')),
+                 json_extract(document, '$.channel.displayName')
+                 from peertube_videos",
+                NO_PARAMS,
+            )
+            .expect("Failed to rebuild the full text index");
+        }
+        SqliteDatabase { conn }
+    }
+}
+
+/// Turns free text into a FTS5 query matching every word, so that user input is never parsed as
+/// FTS5 syntax
+fn fts_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Turns typed text into a FTS5 query matching the words starting with each typed word
+fn fts_prefix_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Turns the name and tags of a video into a FTS5 query matching any of their words
+fn fts_any_query(video: &Video) -> String {
+    std::iter::once(video.name.as_str())
+        .chain(video.tags.iter().map(String::as_str))
+        .flat_map(|text| text.split(|c: char| !c.is_alphanumeric()))
+        .filter(|word| word.chars().count() > 2)
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<String>>()
+        .join(" OR ")
+}
+
+impl SqliteDatabase {
+    fn query_videos<P>(&self, sql: &str, params: P) -> Result<Vec<Video>, Box<dyn Error>>
+    where
+        P: IntoIterator,
+        P::Item: rusqlite::ToSql,
+    {
+        let mut stmt = self.conn.prepare(sql)?;
+        let documents = stmt.query_map(params, |row| row.get::<_, String>(0))?;
+        Ok(documents
+            .filter_map(Result::ok)
+            .filter_map(|document| serde_json::from_str(&document).ok())
+            .collect())
+    }
+}
+
+impl VideoStorage for SqliteDatabase {
+    fn store_videos(&mut self, videos: &[Video]) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        for video in videos {
+            tx.execute(
+                "insert or replace into peertube_videos (uuid, host, hidden, document)
+                 values (?1, ?2, ?3, ?4)",
+                params![
+                    video.uuid,
+                    video.account.host,
+                    video.hidden,
+                    serde_json::to_string(video)?
+                ],
+            )?;
+            tx.execute(
+                "delete from peertube_videos_fts where uuid = ?1",
+                params![video.uuid],
+            )?;
+            tx.execute(
+                "insert into peertube_videos_fts (uuid, name, description, tags, channel)
+                 values (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    video.uuid,
+                    video.name,
+                    video.description,
+                    video.tags.join(" "),
+                    video.channel.display_name
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_video(&self, uuid: &str) -> Result<Option<Video>, Box<dyn Error>> {
+        let document: Option<String> = self
+            .conn
+            .query_row(
+                "select document from peertube_videos where uuid = ?1",
+                params![uuid],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match document {
+            Some(document) => Ok(Some(serde_json::from_str(&document)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn search(&self, query: &SearchQuery) -> Result<Vec<Video>, Box<dyn Error>> {
+        if query.text.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        self.query_videos(
+            "select v.document from peertube_videos_fts f
+             join peertube_videos v on v.uuid = f.uuid
+             where peertube_videos_fts match ?1 and (?2 or v.hidden = 0)
+             order by bm25(peertube_videos_fts, 0.0, 3.0, 1.0, 1.0)
+             limit ?3 offset ?4",
+            params![
+                fts_query(&query.text),
+                query.nsfw,
+                query.size as i64,
+                query.from as i64
+            ],
+        )
+    }
+
+    fn suggest(
+        &self,
+        text: &str,
+        nsfw: bool,
+        size: u64,
+    ) -> Result<Vec<Suggestion>, Box<dyn Error>> {
+        if text.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        let videos = self.query_videos(
+            "select v.document from peertube_videos_fts f
+             join peertube_videos v on v.uuid = f.uuid
+             where peertube_videos_fts match ?1 and (?2 or v.hidden = 0)
+             limit ?3",
+            params![fts_prefix_query(text), nsfw, (size * 3) as i64],
+        )?;
+        Ok(collect_suggestions(&videos, text, size as usize))
+    }
+
+    fn related(&self, video: &Video, nsfw: bool, size: u64) -> Result<Vec<Video>, Box<dyn Error>> {
+        let query = fts_any_query(video);
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        self.query_videos(
+            "select v.document from peertube_videos_fts f
+             join peertube_videos v on v.uuid = f.uuid
+             where peertube_videos_fts match ?1 and (?2 or v.hidden = 0) and v.uuid != ?3
+             order by bm25(peertube_videos_fts, 0.0, 3.0, 1.0, 1.0)
+             limit ?4",
+            params![query, nsfw, video.uuid, size as i64],
+        )
+    }
+
+    fn channel_videos(
+        &self,
+        video: &Video,
+        nsfw: bool,
+        size: u64,
+    ) -> Result<Vec<Video>, Box<dyn Error>> {
+        self.query_videos(
+            "select document from peertube_videos
+             where json_extract(document, '$.channel.url') = ?1
+             and (?2 or hidden = 0) and uuid != ?3
+             order by json_extract(document, '$.publishedAt') desc
+             limit ?4",
+            params![video.channel.url, nsfw, video.uuid, size as i64],
+        )
+    }
+
+    fn list_videos(&self) -> Result<Vec<StoredVideo>, Box<dyn Error>> {
+        let mut stmt = self.conn.prepare(
+            "select uuid, host, json_extract(document, '$.updatedAt') from peertube_videos",
+        )?;
+        let videos = stmt.query_map(NO_PARAMS, |row| {
+            Ok(StoredVideo {
+                uuid: row.get(0)?,
+                host: row.get(1)?,
+                updated_at: row.get(2)?,
+            })
+        })?;
+        Ok(videos.filter_map(Result::ok).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peertube_api::Video;
+    use crate::search::SearchQuery;
+    use crate::sqlite_storage::SqliteDatabase;
+    use crate::video_policy::{NsfwPolicy, VideoPolicy};
+    use crate::video_storage::VideoStorage;
+
+    #[test]
+    fn sqlite_storage() {
+        let json = include_str!("../tests/video1.json");
+        let mut video: Video = serde_json::from_str(json).unwrap();
+        let mut db = SqliteDatabase::new(":memory:");
+        db.store_videos(&[video.clone()]).unwrap();
+        video.nsfw = true;
+        video.hidden = true;
+        db.store_videos(&[video.clone()]).unwrap();
+        assert!(db.get_video(&video.uuid).unwrap().unwrap().nsfw);
+
+        let mut query = SearchQuery::new(video.name.clone());
+        assert!(db.search(&query).unwrap().is_empty());
+        query.nsfw = true;
+        assert_eq!(db.search(&query).unwrap().len(), 1);
+
+        let prefix: String = video.name.chars().take(4).collect();
+        assert!(db.suggest(&prefix, false, 5).unwrap().is_empty());
+        let suggestions = db.suggest(&prefix, true, 5).unwrap();
+        assert_eq!(suggestions[0].text, video.name);
+        let channel: String = video.channel.display_name.chars().take(4).collect();
+        let suggestions = db.suggest(&channel, true, 5).unwrap();
+        assert!(suggestions
+            .iter()
+            .any(|s| s.text == video.channel.display_name));
+        assert!(db.suggest("%", true, 5).unwrap().is_empty());
+
+        let mut sibling = video.clone();
+        sibling.uuid = "sibling".to_string();
+        sibling.nsfw = false;
+        sibling.hidden = false;
+        db.store_videos(&[sibling]).unwrap();
+        assert_eq!(db.related(&video, false, 5).unwrap()[0].uuid, "sibling");
+        assert_eq!(db.channel_videos(&video, false, 5).unwrap().len(), 1);
+        assert!(db
+            .related(&video, false, 5)
+            .unwrap()
+            .iter()
+            .all(|v| v.uuid != video.uuid));
+    }
+
+    #[test]
+    fn nsfw_policy() {
+        let json = include_str!("../tests/video1.json");
+        let mut video: Video = serde_json::from_str(json).unwrap();
+        video.nsfw = true;
+        let query = SearchQuery::new(video.name.clone());
+
+        let mut db = SqliteDatabase::new(":memory:");
+        VideoPolicy::new(NsfwPolicy::Index).apply(&mut video);
+        db.store_videos(&[video.clone()]).unwrap();
+        assert_eq!(db.search(&query).unwrap().len(), 1);
+
+        VideoPolicy::new(NsfwPolicy::Hide).apply(&mut video);
+        db.store_videos(&[video.clone()]).unwrap();
+        assert!(db.search(&query).unwrap().is_empty());
+        assert!(db.related(&video, false, 5).unwrap().is_empty());
+    }
+}
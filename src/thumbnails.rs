@@ -0,0 +1,381 @@
+/// This module proxies the thumbnails of the videos through a disk cache, so that browsers never
+/// contact the instances. Thumbnails are resized to the dimensions used by the search pages, and
+/// the least recently used ones are evicted when the cache exceeds its size.
+use crate::web::{is_valid_id, valid_location};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{ImageReader, Limits};
+use isahc::prelude::*;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/** Largest thumbnail downloaded from an instance */
+const MAX_DOWNLOAD: u64 = 10 * 1024 * 1024;
+
+/** Largest width or height decoded, in pixels */
+const MAX_DIMENSION: u32 = 8192;
+
+const JPEG_QUALITY: u8 = 80;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/** Delay before an instance which failed to answer is contacted again */
+const DEAD_HOST_DELAY: Duration = Duration::from_secs(600);
+
+/** Delay before a thumbnail which could not be downloaded or decoded is requested again */
+const FAILED_DELAY: Duration = Duration::from_secs(3600);
+
+/** Thumbnails waiting for a download thread, beyond which requests get the placeholder only */
+const FETCH_QUEUE_SIZE: usize = 256;
+
+/// Dimensions of the cached images
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbnailSize {
+    /// Search results and video lists, from the thumbnail of the video
+    Small,
+    /// Video pages, from the preview of the video
+    Large,
+}
+
+impl ThumbnailSize {
+    pub fn dimensions(self) -> (u32, u32) {
+        match self {
+            ThumbnailSize::Small => (320, 180),
+            ThumbnailSize::Large => (640, 360),
+        }
+    }
+
+    fn dir(self) -> &'static str {
+        match self {
+            ThumbnailSize::Small => "small",
+            ThumbnailSize::Large => "large",
+        }
+    }
+}
+
+/// Decodes an image, crops it to the ratio of the size and resizes it, as a JPEG
+pub fn resize(data: &[u8], size: ThumbnailSize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    let (width, height) = size.dimensions();
+    let image = reader
+        .decode()?
+        .resize_to_fill(width, height, FilterType::Triangle);
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&image.to_rgb8())?;
+    Ok(jpeg)
+}
+
+/// A cached file, with its last use and size
+type CachedFile = (SystemTime, u64, PathBuf);
+
+/// The mutable part of the cache, shared by the threads fetching thumbnails
+struct CacheState {
+    /// Size of the cached files, updated as files are added and evicted
+    total_bytes: u64,
+    /// Instances which failed to answer, with the time of the failure
+    dead_hosts: HashMap<String, Instant>,
+    /// Thumbnails which could not be downloaded or decoded, with the time of the failure
+    failed: HashMap<PathBuf, Instant>,
+}
+
+/// Resized thumbnails, stored as `<dir>/<size>/<key>.jpg`
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    client: HttpClient,
+    state: Mutex<CacheState>,
+}
+
+impl ThumbnailCache {
+    pub fn open(dir: PathBuf, max_bytes: u64) -> Result<ThumbnailCache, Box<dyn Error>> {
+        for size in &[ThumbnailSize::Small, ThumbnailSize::Large] {
+            fs::create_dir_all(dir.join(size.dir()))?;
+        }
+        let cache = ThumbnailCache {
+            dir,
+            max_bytes,
+            client: HttpClient::new()?,
+            state: Mutex::new(CacheState {
+                total_bytes: 0,
+                dead_hosts: HashMap::new(),
+                failed: HashMap::new(),
+            }),
+        };
+        let mut state = cache.lock();
+        state.total_bytes = cache.files()?.iter().map(|(_, len, _)| len).sum();
+        info!(
+            "{} MB of thumbnails in {:?}",
+            state.total_bytes / 1024 / 1024,
+            cache.dir
+        );
+        cache.evict(&mut state)?;
+        drop(state);
+        Ok(cache)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn path(&self, key: &str, size: ThumbnailSize) -> Option<PathBuf> {
+        // Keys name the cached files, and must not hold any path separator
+        if is_valid_id(key) {
+            Some(self.dir.join(size.dir()).join(format!("{}.jpg", key)))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a cached thumbnail, marking it as recently used
+    pub fn get(&self, key: &str, size: ThumbnailSize) -> Option<Vec<u8>> {
+        let path = self.path(key, size)?;
+        let data = fs::read(&path).ok()?;
+        if let Err(e) = fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            warn!("Failed to mark {:?} as used : {}", path, e);
+        }
+        Some(data)
+    }
+
+    /// Downloads `https://<host><path>`, resizes it and caches it under the key. Instances
+    /// failing to answer are not contacted again for a while, nor are the thumbnails which could
+    /// not be downloaded or decoded requested again.
+    pub fn fetch(
+        &self,
+        key: &str,
+        size: ThumbnailSize,
+        host: &str,
+        path: &str,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let file = self
+            .path(key, size)
+            .ok_or_else(|| format!("Invalid thumbnail key {}", key))?;
+        if !valid_location(host, path) {
+            return Err(format!("Invalid thumbnail location {} {}", host, path).into());
+        }
+        {
+            let mut state = self.lock();
+            if let Some(failed) = state.failed.get(&file) {
+                if failed.elapsed() < FAILED_DELAY {
+                    return Err(format!("{}{} failed recently", host, path).into());
+                }
+                state.failed.remove(&file);
+            }
+            if let Some(failed) = state.dead_hosts.get(host) {
+                if failed.elapsed() < DEAD_HOST_DELAY {
+                    return Err(format!("{} is unreachable", host).into());
+                }
+                state.dead_hosts.remove(host);
+            }
+        }
+        let request = Request::get(format!("https://{}{}", host, path))
+            .timeout(FETCH_TIMEOUT)
+            .body(())?;
+        let mut resp = match self.client.send(request) {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.lock()
+                    .dead_hosts
+                    .insert(host.to_string(), Instant::now());
+                return Err(e.into());
+            }
+        };
+        let thumbnail = download(&mut resp, host, path).and_then(|data| resize(&data, size));
+        let thumbnail = match thumbnail {
+            Ok(thumbnail) => thumbnail,
+            Err(e) => {
+                self.lock().failed.insert(file, Instant::now());
+                return Err(e);
+            }
+        };
+        self.store(&file, &thumbnail)?;
+        Ok(thumbnail)
+    }
+
+    fn store(&self, file: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut state = self.lock();
+        let previous = fs::metadata(file)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        fs::write(file, data)?;
+        state.total_bytes = state.total_bytes - previous.min(state.total_bytes) + data.len() as u64;
+        self.evict(&mut state)
+    }
+
+    /// Lists the cached files with their size and last use
+    fn files(&self) -> Result<Vec<CachedFile>, Box<dyn Error>> {
+        let mut files = vec![];
+        for size in &[ThumbnailSize::Small, ThumbnailSize::Large] {
+            for entry in fs::read_dir(self.dir.join(size.dir()))? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    files.push((used, metadata.len(), entry.path()));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Removes the least recently used thumbnails once the cache exceeds its size, down to 90%
+    /// of it so that eviction does not run on every new thumbnail
+    fn evict(&self, state: &mut CacheState) -> Result<(), Box<dyn Error>> {
+        if state.total_bytes <= self.max_bytes {
+            return Ok(());
+        }
+        let mut files = self.files()?;
+        files.sort();
+        state.total_bytes = files.iter().map(|(_, len, _)| len).sum();
+        let target = self.max_bytes / 10 * 9;
+        for (_, len, path) in files {
+            if state.total_bytes <= target {
+                break;
+            }
+            fs::remove_file(&path)?;
+            state.total_bytes -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a thumbnail answered by an instance, up to `MAX_DOWNLOAD` bytes
+fn download(resp: &mut Response<Body>, host: &str, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !resp.status().is_success() {
+        return Err(format!("{}{} answered {}", host, path, resp.status()).into());
+    }
+    let mut data = vec![];
+    resp.body_mut()
+        .take(MAX_DOWNLOAD + 1)
+        .read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_DOWNLOAD {
+        return Err(format!("{}{} is larger than {} bytes", host, path, MAX_DOWNLOAD).into());
+    }
+    Ok(data)
+}
+
+/// A thumbnail to download from `https://<host><path>`
+struct Job {
+    key: String,
+    size: ThumbnailSize,
+    host: String,
+    path: String,
+}
+
+/// Downloads the missing thumbnails on a pool of threads, so that requests are answered without
+/// waiting for the instances
+pub struct ThumbnailFetcher {
+    cache: Arc<ThumbnailCache>,
+    jobs: SyncSender<Job>,
+    /// Thumbnails queued or being downloaded, by key and size
+    pending: Arc<Mutex<HashSet<(String, &'static str)>>>,
+}
+
+impl ThumbnailFetcher {
+    pub fn new(cache: ThumbnailCache, threads: usize) -> ThumbnailFetcher {
+        let cache = Arc::new(cache);
+        let (jobs, receiver) = sync_channel::<Job>(FETCH_QUEUE_SIZE);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        for _ in 0..threads.max(1) {
+            let (cache, receiver, pending) = (cache.clone(), receiver.clone(), pending.clone());
+            thread::spawn(move || loop {
+                let job = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                if let Err(e) = cache.fetch(&job.key, job.size, &job.host, &job.path) {
+                    debug!("Failed to fetch the thumbnail of {} : {}", job.key, e);
+                }
+                pending
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&(job.key, job.size.dir()));
+            });
+        }
+        ThumbnailFetcher {
+            cache,
+            jobs,
+            pending,
+        }
+    }
+
+    pub fn cache(&self) -> &ThumbnailCache {
+        &self.cache
+    }
+
+    /// Queues the download of a thumbnail, unless it is already queued or the queue is full
+    pub fn queue(&self, key: &str, size: ThumbnailSize, host: &str, path: &str) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        if !pending.insert((key.to_string(), size.dir())) {
+            return;
+        }
+        let job = Job {
+            key: key.to_string(),
+            size,
+            host: host.to_string(),
+            path: path.to_string(),
+        };
+        if self.jobs.try_send(job).is_err() {
+            pending.remove(&(key.to_string(), size.dir()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::thumbnails::{resize, ThumbnailCache, ThumbnailSize};
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn thumbnails() {
+        let mut png = vec![];
+        RgbImage::new(400, 400)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let jpeg = resize(&png, ThumbnailSize::Small).unwrap();
+        let resized = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((resized.width(), resized.height()), (320, 180));
+        assert!(resize(b"not an image", ThumbnailSize::Small).is_err());
+
+        let dir =
+            std::env::temp_dir().join(format!("peertube_se_thumbnails_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = ThumbnailCache::open(dir.clone(), 2 * jpeg.len() as u64).unwrap();
+        assert!(cache.path("../instances", ThumbnailSize::Small).is_none());
+        for (host, path) in &[
+            ("user@internal", "/thumbnail.jpg"),
+            ("localhost:9200", "/thumbnail.jpg"),
+            ("video.example", "@internal/thumbnail.jpg"),
+            ("video.example", "//internal/thumbnail.jpg"),
+        ] {
+            assert!(cache
+                .fetch("key", ThumbnailSize::Small, host, path)
+                .is_err());
+        }
+        for key in &["first", "second", "third"] {
+            let path = cache.path(key, ThumbnailSize::Small).unwrap();
+            cache.store(&path, &jpeg).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(cache.get("first", ThumbnailSize::Small).is_none());
+        assert_eq!(cache.get("third", ThumbnailSize::Small), Some(jpeg));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
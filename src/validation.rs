@@ -0,0 +1,239 @@
+/// This module checks the crawl files before they are indexed
+use crate::crawl_output::{crawl_file_host, open_crawl_file};
+use crate::peertube_api::Video;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io;
+use std::io::BufRead;
+use std::path::Path;
+
+/** Number of problems reported in details for each file */
+const MAX_SAMPLES: usize = 20;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum Issue {
+    /// The line is not valid JSON
+    Malformed(String),
+    /// The file could not be read past this line, e.g. a compressed file cut short
+    Truncated(String),
+    /// The line is a JSON object lacking a field of videos
+    MissingField(String),
+    /// A field of the video has the wrong type
+    InvalidType(String),
+    NegativeValue(&'static str),
+    InvalidPublishedAt(String),
+    FuturePublishedAt(String),
+    HostMismatch(String),
+}
+
+/// Checks the values of a video crawled from `host`
+pub fn validate_video(video: &Video, host: &str, now: DateTime<Utc>) -> Vec<Issue> {
+    let mut issues = vec![];
+    for (field, value) in &[
+        ("views", video.views),
+        ("duration", video.duration),
+        ("likes", video.likes),
+        ("dislikes", video.dislikes),
+    ] {
+        if *value < 0 {
+            issues.push(Issue::NegativeValue(field));
+        }
+    }
+    match DateTime::parse_from_rfc3339(&video.published_at) {
+        Ok(published_at) if published_at > now => {
+            issues.push(Issue::FuturePublishedAt(video.published_at.clone()))
+        }
+        Ok(_) => (),
+        Err(_) => issues.push(Issue::InvalidPublishedAt(video.published_at.clone())),
+    }
+    if video.account.host != host {
+        issues.push(Issue::HostMismatch(video.account.host.clone()));
+    }
+    issues
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LineIssue {
+    pub line: usize,
+    #[serde(flatten)]
+    pub issue: Issue,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct FileReport {
+    pub file: String,
+    pub host: String,
+    pub lines: usize,
+    pub valid: usize,
+    pub malformed: usize,
+    /// Whether the end of the file could not be read
+    pub truncated: bool,
+    pub missing_fields: usize,
+    pub invalid_types: usize,
+    pub negative_values: usize,
+    pub invalid_dates: usize,
+    pub future_dates: usize,
+    pub host_mismatches: usize,
+    /// The first problems found in the file
+    pub samples: Vec<LineIssue>,
+}
+
+impl FileReport {
+    pub fn is_valid(&self) -> bool {
+        self.valid == self.lines && !self.truncated
+    }
+
+    fn add(&mut self, line: usize, issue: Issue) {
+        match issue {
+            Issue::Malformed(_) => self.malformed += 1,
+            Issue::Truncated(_) => self.truncated = true,
+            Issue::MissingField(_) => self.missing_fields += 1,
+            Issue::InvalidType(_) => self.invalid_types += 1,
+            Issue::NegativeValue(_) => self.negative_values += 1,
+            Issue::InvalidPublishedAt(_) => self.invalid_dates += 1,
+            Issue::FuturePublishedAt(_) => self.future_dates += 1,
+            Issue::HostMismatch(_) => self.host_mismatches += 1,
+        }
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(LineIssue { line, issue });
+        }
+    }
+}
+
+/// Tells a missing field from a field of the wrong type, which serde reports alike
+fn video_error(e: serde_json::Error) -> Issue {
+    let message = e.to_string();
+    if message.starts_with("missing field") {
+        Issue::MissingField(message)
+    } else {
+        Issue::InvalidType(message)
+    }
+}
+
+/// Checks every line of a crawl file, without stopping at the first problem. Only failing to
+/// open the file is an error : a file that cannot be read to its end is reported as truncated.
+pub fn validate_file(path: &Path, now: DateTime<Utc>) -> io::Result<FileReport> {
+    let host = crawl_file_host(path).unwrap_or_default();
+    let mut report = FileReport {
+        file: path.to_string_lossy().to_string(),
+        host: host.clone(),
+        ..FileReport::default()
+    };
+    for (index, line) in open_crawl_file(path)?.split(b'\n').enumerate() {
+        let line_number = index + 1;
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                report.add(line_number, Issue::Truncated(e.to_string()));
+                break;
+            }
+        };
+        report.lines += 1;
+        let value: serde_json::Value = match serde_json::from_slice(&line) {
+            Ok(value) => value,
+            Err(e) => {
+                report.add(line_number, Issue::Malformed(e.to_string()));
+                continue;
+            }
+        };
+        match serde_json::from_value::<Video>(value) {
+            Ok(video) => {
+                let issues = validate_video(&video, &host, now);
+                if issues.is_empty() {
+                    report.valid += 1;
+                }
+                for issue in issues {
+                    report.add(line_number, issue);
+                }
+            }
+            Err(e) => report.add(line_number, video_error(e)),
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ValidationSummary {
+    pub valid: bool,
+    pub files: usize,
+    pub invalid_files: usize,
+    pub lines: usize,
+    pub valid_lines: usize,
+    pub reports: Vec<FileReport>,
+}
+
+impl ValidationSummary {
+    pub fn new(reports: Vec<FileReport>) -> ValidationSummary {
+        let invalid_files = reports.iter().filter(|r| !r.is_valid()).count();
+        ValidationSummary {
+            valid: invalid_files == 0,
+            files: reports.len(),
+            invalid_files,
+            lines: reports.iter().map(|r| r.lines).sum(),
+            valid_lines: reports.iter().map(|r| r.valid).sum(),
+            reports,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peertube_api::Video;
+    use crate::validation::{validate_file, validate_video, Issue};
+    use chrono::Utc;
+    use std::io::Write;
+
+    #[test]
+    fn validation() {
+        let json = include_str!("../tests/video1.json");
+        let mut video: Video = serde_json::from_str(json).unwrap();
+        let host = video.account.host.clone();
+        assert!(validate_video(&video, &host, Utc::now()).is_empty());
+
+        video.views = -1;
+        video.published_at = "2999-01-01T00:00:00.000Z".to_string();
+        assert_eq!(
+            validate_video(&video, "other.example", Utc::now()),
+            vec![
+                Issue::NegativeValue("views"),
+                Issue::FuturePublishedAt("2999-01-01T00:00:00.000Z".to_string()),
+                Issue::HostMismatch(host),
+            ]
+        );
+        video.published_at = "yesterday".to_string();
+        assert_eq!(
+            validate_video(&video, &video.account.host.clone(), Utc::now()),
+            vec![
+                Issue::NegativeValue("views"),
+                Issue::InvalidPublishedAt("yesterday".to_string())
+            ]
+        );
+
+        let dir =
+            std::env::temp_dir().join(format!("peertube_se_validation_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("raptube.antipub.org.json.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut wrong_type: serde_json::Value = serde_json::from_str(json).unwrap();
+        wrong_type["views"] = "many".into();
+        for line in &[
+            json.replace('\n', ""),
+            wrong_type.to_string(),
+            "{}".to_string(),
+        ] {
+            encoder.write_all(line.as_bytes()).unwrap();
+            encoder.write_all(b"\n").unwrap();
+        }
+        encoder.write_all(b"\xff\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        std::fs::write(&path, &compressed[..compressed.len() - 4]).unwrap();
+        let report = validate_file(&path, Utc::now()).unwrap();
+        assert_eq!(
+            (report.valid, report.invalid_types, report.missing_fields),
+            (1, 1, 1)
+        );
+        assert_eq!((report.malformed, report.truncated), (1, true));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+/// This module decides how NSFW and blacklisted videos are indexed
+use crate::peertube_api::Video;
+use std::str::FromStr;
+
+/// What to do with videos flagged as NSFW
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NsfwPolicy {
+    /// Do not index them
+    Skip,
+    /// Index them, but only show them in searches that ask for NSFW content
+    #[default]
+    Hide,
+    /// Index them like any other video
+    Index,
+}
+
+impl FromStr for NsfwPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(NsfwPolicy::Skip),
+            "hide" => Ok(NsfwPolicy::Hide),
+            "index" => Ok(NsfwPolicy::Index),
+            _ => Err(format!(
+                "Unknown NSFW policy {} (expected skip, hide or index)",
+                s
+            )),
+        }
+    }
+}
+
+/// The outcome of the policy for a single video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PolicyBucket {
+    Indexed,
+    Hidden,
+    SkippedNsfw,
+    SkippedBlacklisted,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PolicyStats {
+    pub indexed: u64,
+    pub hidden: u64,
+    pub skipped_nsfw: u64,
+    pub skipped_blacklisted: u64,
+}
+
+impl PolicyStats {
+    pub fn add(&mut self, bucket: PolicyBucket) {
+        match bucket {
+            PolicyBucket::Indexed => self.indexed += 1,
+            PolicyBucket::Hidden => self.hidden += 1,
+            PolicyBucket::SkippedNsfw => self.skipped_nsfw += 1,
+            PolicyBucket::SkippedBlacklisted => self.skipped_blacklisted += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VideoPolicy {
+    pub nsfw: NsfwPolicy,
+}
+
+impl VideoPolicy {
+    pub fn new(nsfw: NsfwPolicy) -> VideoPolicy {
+        VideoPolicy { nsfw }
+    }
+
+    /// Sorts a video into a bucket, marking it as hidden when needed.
+    /// Blacklisted videos are never indexed.
+    pub fn apply(&self, video: &mut Video) -> PolicyBucket {
+        if video.blacklisted.unwrap_or(false) {
+            return PolicyBucket::SkippedBlacklisted;
+        }
+        video.hidden = false;
+        if !video.nsfw {
+            return PolicyBucket::Indexed;
+        }
+        match self.nsfw {
+            NsfwPolicy::Skip => PolicyBucket::SkippedNsfw,
+            NsfwPolicy::Hide => {
+                video.hidden = true;
+                PolicyBucket::Hidden
+            }
+            NsfwPolicy::Index => PolicyBucket::Indexed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peertube_api::Video;
+    use crate::video_policy::{NsfwPolicy, PolicyBucket, VideoPolicy};
+
+    #[test]
+    fn video_policy() {
+        let json = include_str!("../tests/video1.json");
+        let mut video: Video = serde_json::from_str(json).unwrap();
+        video.nsfw = true;
+        let policy = VideoPolicy::new(NsfwPolicy::Hide);
+        assert_eq!(policy.apply(&mut video), PolicyBucket::Hidden);
+        assert!(video.hidden);
+        let policy = VideoPolicy::new(NsfwPolicy::Index);
+        assert_eq!(policy.apply(&mut video), PolicyBucket::Indexed);
+        assert!(!video.hidden);
+        video.blacklisted = Some(true);
+        assert_eq!(policy.apply(&mut video), PolicyBucket::SkippedBlacklisted);
+    }
+}
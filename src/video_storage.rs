@@ -1,90 +1,209 @@
-/// This module is used to store videos in the Elastic database
+/// This module is used to store videos, either in the Elastic database or in SQLite
+use crate::elastic::{to_document, Elastic, EsConfig, EsError, INDEX_ALIAS};
 use crate::peertube_api::Video;
-use elastic::client::requests::common::Doc;
-use elastic::client::requests::{
-    DeleteRequestBuilder, GetRequestBuilder, IndexRequestBuilder, UpdateRequestBuilder,
+use crate::search::{
+    channel_videos, related_videos, search_videos, suggest_videos, SearchQuery, Suggestion,
 };
-use elastic::client::{AsyncClientBuilder, Client};
-use elastic::http::sender::AsyncSender;
-use futures::future::{ok, Future};
-use log::warn;
+use crate::sqlite_storage::SqliteDatabase;
+use crate::web::percent_encode;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::error::Error;
+use std::str::FromStr;
 
-type DatabaseSender = AsyncSender;
-type DatabaseClient = Client<DatabaseSender>;
-use std::sync::Arc;
+/** Number of documents fetched by each scroll request */
+const SCROLL_SIZE: u64 = 1000;
+
+/// The fields of a stored video needed to compare it with a crawl
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoredVideo {
+    pub uuid: String,
+    pub host: String,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: String,
+}
+
+/// A database of videos, used by the crawler, the indexer and the search
+pub trait VideoStorage: Send {
+    /// Inserts or replaces videos, using their uuid as the key
+    fn store_videos(&mut self, videos: &[Video]) -> Result<(), Box<dyn Error>>;
+
+    fn get_video(&self, uuid: &str) -> Result<Option<Video>, Box<dyn Error>>;
+
+    fn search(&self, query: &SearchQuery) -> Result<Vec<Video>, Box<dyn Error>>;
+
+    /// Completes the text typed in the search box with the names, channels and tags of videos
+    fn suggest(&self, text: &str, nsfw: bool, size: u64)
+        -> Result<Vec<Suggestion>, Box<dyn Error>>;
+
+    /// Finds the videos most similar to a video, by name, description and tags
+    fn related(&self, video: &Video, nsfw: bool, size: u64) -> Result<Vec<Video>, Box<dyn Error>>;
+
+    /// Lists the other videos of the channel of a video, newest first
+    fn channel_videos(
+        &self,
+        video: &Video,
+        nsfw: bool,
+        size: u64,
+    ) -> Result<Vec<Video>, Box<dyn Error>>;
+
+    /// Lists every stored video
+    fn list_videos(&self) -> Result<Vec<StoredVideo>, Box<dyn Error>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    Elastic,
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "elastic" => Ok(StorageKind::Elastic),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(format!(
+                "Unknown storage {} (expected elastic or sqlite)",
+                s
+            )),
+        }
+    }
+}
+
+/// Opens the requested storage, creating the index or the tables if needed
+pub fn open_storage(
+    kind: StorageKind,
+    config: &EsConfig,
+) -> Result<Box<dyn VideoStorage>, Box<dyn Error>> {
+    Ok(match kind {
+        StorageKind::Elastic => Box::new(ElasticDatabase::new(config)?),
+        StorageKind::Sqlite => Box::new(SqliteDatabase::default()),
+    })
+}
 
 /// An Elastic database that allows to store videos
-pub struct Database {
-    elastic_client: DatabaseClient,
+pub struct ElasticDatabase {
+    elastic: Elastic,
 }
 
-impl Default for Database {
-    fn default() -> Database {
-        let elastic_client = AsyncClientBuilder::new()
-            .build()
-            .expect("Failed to create elastic client");
-        Database { elastic_client }
+impl ElasticDatabase {
+    /// Connects to the cluster, checking its health before creating or updating the index
+    pub fn new(config: &EsConfig) -> Result<ElasticDatabase, EsError> {
+        let elastic = Elastic::new(config)?;
+        let health = elastic.health()?;
+        info!(
+            "Connected to Elastic Search {} ({})",
+            health.version, health.status
+        );
+        elastic.create_mappings()?;
+        Ok(ElasticDatabase { elastic })
     }
 }
 
-impl Database {
-    pub fn new(elastic_client: DatabaseClient) -> Database {
-        Database { elastic_client }
+impl VideoStorage for ElasticDatabase {
+    fn store_videos(&mut self, videos: &[Video]) -> Result<(), Box<dyn Error>> {
+        if videos.is_empty() {
+            return Ok(());
+        }
+        // The bulk API expects an action line followed by the document, for each video
+        let mut body = String::new();
+        for video in videos {
+            body += &json!({ "index": { "_id": video.uuid } }).to_string();
+            body += "\n";
+            body += &to_document(video)?.to_string();
+            body += "\n";
+        }
+        let json = self
+            .elastic
+            .post_ndjson(&format!("/{}/_bulk", INDEX_ALIAS), body)?;
+        if json["errors"].as_bool().unwrap_or(true) {
+            return Err(EsError::Rejected(json.to_string()).into());
+        }
+        Ok(())
     }
 
-    pub fn video_is_present(&self, video: &Video) -> GetRequestBuilder<DatabaseSender, Video> {
-        self.elastic_client
-            .document::<Video>()
-            .get(video.id.clone())
+    fn get_video(&self, uuid: &str) -> Result<Option<Video>, Box<dyn Error>> {
+        match self.elastic.get_optional(&format!(
+            "/{}/_doc/{}",
+            INDEX_ALIAS,
+            percent_encode(uuid)
+        ))? {
+            Some(json) if json["found"].as_bool().unwrap_or(false) => Ok(Some(
+                serde_json::from_value(json["_source"].clone()).map_err(EsError::from)?,
+            )),
+            _ => Ok(None),
+        }
     }
 
-    pub fn delete_video(&self, video: &Video) -> DeleteRequestBuilder<DatabaseSender, Video> {
-        self.elastic_client
-            .document::<Video>()
-            .delete(video.id.clone())
+    fn search(&self, query: &SearchQuery) -> Result<Vec<Video>, Box<dyn Error>> {
+        Ok(search_videos(&self.elastic, query)?)
     }
 
-    pub fn update_video(&self, video: &Video) -> UpdateRequestBuilder<DatabaseSender, Doc<Video>> {
-        self.elastic_client
-            .document::<Video>()
-            .update(video.id.clone())
-            .doc(video.clone())
+    fn suggest(
+        &self,
+        text: &str,
+        nsfw: bool,
+        size: u64,
+    ) -> Result<Vec<Suggestion>, Box<dyn Error>> {
+        Ok(suggest_videos(&self.elastic, text, nsfw, size)?)
     }
 
-    pub fn get_video(&self, video: &Video) -> GetRequestBuilder<DatabaseSender, Video> {
-        self.elastic_client
-            .document::<Video>()
-            .get(video.id.clone())
+    fn related(&self, video: &Video, nsfw: bool, size: u64) -> Result<Vec<Video>, Box<dyn Error>> {
+        Ok(related_videos(&self.elastic, &video.uuid, nsfw, size)?)
     }
 
-    pub fn index_video(&self, video: &Video) -> IndexRequestBuilder<DatabaseSender, Video> {
-        self.elastic_client.document::<Video>().index(video.clone())
+    fn channel_videos(
+        &self,
+        video: &Video,
+        nsfw: bool,
+        size: u64,
+    ) -> Result<Vec<Video>, Box<dyn Error>> {
+        Ok(channel_videos(&self.elastic, video, nsfw, size)?)
     }
-}
 
-fn process_video(db: Arc<Database>, video: Arc<Video>) -> impl Future<Item = (), Error = ()> {
-    let db_handle = db.clone();
-    let video_handle = video.clone();
-    let future = db_handle
-        .update_video(&video)
-        .send()
-        .and_then(|_| ok(()))
-        .map_err(move |_| {
-            tokio::spawn(
-                db.index_video(&video_handle)
-                    .send()
-                    .and_then(|_| ok(()))
-                    .map_err(|e| warn!("{}", e)),
-            );
+    fn list_videos(&self) -> Result<Vec<StoredVideo>, Box<dyn Error>> {
+        let mut result = vec![];
+        let mut scroll_id: Option<String>;
+        let mut path = format!("/{}/_search?scroll=1m", INDEX_ALIAS);
+        let mut body = json!({
+            "size": SCROLL_SIZE,
+            "sort": ["_doc"],
+            "_source": ["uuid", "updatedAt", "account.host"]
         });
-    Box::new(future)
-}
-
-pub fn process_videos(db: Database, videos: Vec<Video>) {
-    let db_handle = Arc::new(db);
-    for video in videos {
-        let video_handle = Arc::new(video);
-        let future = process_video(db_handle.clone(), video_handle);;
-        tokio::spawn(future);
+        loop {
+            let json = self.elastic.post(&path, &body)?;
+            scroll_id = json["_scroll_id"].as_str().map(String::from);
+            let hits = match json["hits"]["hits"].as_array() {
+                Some(hits) if !hits.is_empty() => hits,
+                Some(_) => break,
+                None => return Err(EsError::InvalidResponse(json.to_string()).into()),
+            };
+            for hit in hits {
+                let source = &hit["_source"];
+                result.push(StoredVideo {
+                    uuid: source["uuid"].as_str().unwrap_or_default().to_string(),
+                    host: source["account"]["host"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    updated_at: source["updatedAt"].as_str().unwrap_or_default().to_string(),
+                });
+            }
+            path = "/_search/scroll".to_string();
+            body = json!({ "scroll": "1m", "scroll_id": scroll_id });
+        }
+        // Frees the search context rather than waiting for it to expire
+        if let Some(scroll_id) = scroll_id {
+            if let Err(e) = self
+                .elastic
+                .delete("/_search/scroll", &json!({ "scroll_id": scroll_id }))
+            {
+                warn!("Failed to clear the scroll : {}", e);
+            }
+        }
+        Ok(result)
     }
 }
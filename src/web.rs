@@ -0,0 +1,151 @@
+/// This module holds the request helpers of the search server
+use std::collections::HashMap;
+
+/// Decodes a `application/x-www-form-urlencoded` component, `+` standing for a space
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encodes a query parameter, leaving only unreserved characters as is
+pub fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Whether an identifier taken from a request, such as a uuid, is safe to put in a path : it must
+/// only hold letters, digits, `-` and `_`
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether a URL made of a host and a path taken from crawled data stays on that host : the host
+/// must not hold credentials, a port, a path, a query or a fragment, and the path must not be
+/// protocol relative
+pub fn valid_location(host: &str, path: &str) -> bool {
+    !host.is_empty()
+        && !host.contains(['@', '/', ':', '\\', '?', '#'])
+        && !host.contains(char::is_whitespace)
+        && path.starts_with('/')
+        && !path.starts_with("//")
+}
+
+/// Builds `https://<host><path>` from crawled data, unless it would leave the host
+pub fn instance_url(host: &str, path: &str) -> Option<String> {
+    if valid_location(host, path) {
+        Some(format!("https://{}{}", host, path))
+    } else {
+        None
+    }
+}
+
+/// Splits a request URL into its decoded path and query parameters
+pub fn parse_url(url: &str) -> (String, HashMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+    (percent_decode(path), params)
+}
+
+/// Formats a duration in seconds as `m:ss`, or `h:mm:ss` for an hour or more
+pub fn format_duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Content type of a static file, from its extension
+pub fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or_default() {
+        "html" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::web::{
+        content_type, format_duration, instance_url, is_valid_id, parse_url, percent_decode,
+        percent_encode, valid_location,
+    };
+
+    #[test]
+    fn web() {
+        assert_eq!(percent_decode("caf%C3%A9+noir"), "café noir");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_encode("café noir"), "caf%C3%A9%20noir");
+        assert_eq!(percent_decode(&percent_encode("a&b=c")), "a&b=c");
+        let (path, params) = parse_url("/search?query=peer+tube&page=2&nsfw");
+        assert_eq!(path, "/search");
+        assert_eq!(params["query"], "peer tube");
+        assert_eq!(params["page"], "2");
+        assert_eq!(params["nsfw"], "");
+        assert_eq!(content_type("static/index.css"), "text/css");
+        assert!(is_valid_id("9e672bff-0bc5-4021-8a50-7dce52d0edfa"));
+        assert!(!is_valid_id("../_search"));
+        assert!(!is_valid_id(""));
+        assert!(valid_location("peertube.example", "/videos/embed/a"));
+        assert!(!valid_location("peertube.example", "//evil.example/a"));
+        for host in &[
+            "",
+            "user@evil.example",
+            "evil.example:8080",
+            "evil.example/x",
+            "a b",
+        ] {
+            assert!(!valid_location(host, "/a"));
+        }
+        assert_eq!(
+            instance_url("peertube.example", "/videos/watch/a").unwrap(),
+            "https://peertube.example/videos/watch/a"
+        );
+        assert!(instance_url("evil.example?", "/a").is_none());
+        assert_eq!(format_duration(567), "9:27");
+        assert_eq!(format_duration(3723), "1:02:03");
+    }
+}
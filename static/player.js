@@ -0,0 +1,16 @@
+// Loads the player of the instance only once asked to, so that opening a video page does not
+// contact the instance of the video
+(function () {
+    var player = document.getElementById("player");
+    player.querySelector("button").addEventListener("click", function () {
+        var iframe = document.createElement("iframe");
+        iframe.className = "has-ratio";
+        iframe.src = player.dataset.embed + "?autoplay=1";
+        iframe.title = player.dataset.title;
+        iframe.setAttribute("sandbox", "allow-same-origin allow-scripts allow-popups");
+        iframe.setAttribute("allow", "autoplay; fullscreen");
+        iframe.allowFullscreen = true;
+        player.innerHTML = "";
+        player.appendChild(iframe);
+    });
+})();
@@ -0,0 +1,96 @@
+// Shows suggestions under the search boxes as the user types, from the /suggest endpoint
+(function () {
+    var DELAY = 150;
+
+    function attach(input) {
+        var list = document.createElement("ul");
+        list.className = "suggestions";
+        list.hidden = true;
+        input.parentNode.style.position = "relative";
+        input.parentNode.appendChild(list);
+        input.setAttribute("autocomplete", "off");
+
+        var timer = null;
+        var latest = 0;
+        var selected = -1;
+
+        function close() {
+            list.hidden = true;
+            selected = -1;
+        }
+
+        function choose(text) {
+            input.value = text;
+            close();
+            input.form.submit();
+        }
+
+        function highlight(index) {
+            var items = list.children;
+            if (items.length === 0) {
+                return;
+            }
+            selected = (index + items.length) % items.length;
+            for (var i = 0; i < items.length; i++) {
+                items[i].classList.toggle("selected", i === selected);
+            }
+        }
+
+        function show(suggestions) {
+            list.innerHTML = "";
+            suggestions.forEach(function (suggestion) {
+                var item = document.createElement("li");
+                item.textContent = suggestion.text;
+                var kind = document.createElement("span");
+                kind.className = "kind";
+                kind.textContent = suggestion.kind;
+                item.appendChild(kind);
+                item.addEventListener("mousedown", function (event) {
+                    event.preventDefault();
+                    choose(suggestion.text);
+                });
+                list.appendChild(item);
+            });
+            selected = -1;
+            list.hidden = suggestions.length === 0;
+        }
+
+        function fetchSuggestions() {
+            var request = ++latest;
+            fetch("/suggest?query=" + encodeURIComponent(input.value))
+                .then(function (response) { return response.json(); })
+                .then(function (suggestions) {
+                    // Answers to older keystrokes are dropped
+                    if (request === latest) {
+                        show(suggestions);
+                    }
+                })
+                .catch(close);
+        }
+
+        input.addEventListener("input", function () {
+            clearTimeout(timer);
+            timer = setTimeout(fetchSuggestions, DELAY);
+        });
+        input.addEventListener("keydown", function (event) {
+            if (list.hidden) {
+                return;
+            }
+            if (event.key === "ArrowDown") {
+                event.preventDefault();
+                highlight(selected + 1);
+            } else if (event.key === "ArrowUp") {
+                event.preventDefault();
+                highlight(selected - 1);
+            } else if (event.key === "Enter" && selected >= 0) {
+                event.preventDefault();
+                choose(list.children[selected].firstChild.textContent);
+            } else if (event.key === "Escape") {
+                close();
+            }
+        });
+        input.addEventListener("blur", close);
+    }
+
+    document.querySelectorAll("form input[name=query]").forEach(attach);
+})();